@@ -0,0 +1,82 @@
+use error::BufferFull;
+
+// One command byte for the keyboard, and the argument byte that follows it if it takes one.
+#[derive(Clone, Copy)]
+pub struct AtCommand {
+    pub cmd : u8,
+    pub arg : Option<u8>,
+}
+
+impl AtCommand {
+    pub const fn new(cmd : u8, arg : Option<u8>) -> AtCommand {
+        AtCommand {
+            cmd : cmd,
+            arg : arg,
+        }
+    }
+}
+
+// The command waiting to go out to the keyboard, if any. One is all that's ever needed:
+// the only queued commands are an LED update and a typematic rate, and a second one put
+// while the first is still waiting makes queue_at_command() send the first right away.
+// Three bytes of RAM, where a ring of four took fourteen.
+pub struct AtCommandQueue {
+    pending : Option<AtCommand>,
+}
+
+impl AtCommandQueue {
+    pub const fn new() -> AtCommandQueue {
+        AtCommandQueue {
+            pending : None,
+        }
+    }
+
+    pub fn flush(&mut self) -> () {
+        self.pending = None;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_none()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    pub fn put(&mut self, cmd : AtCommand) -> Result<(), BufferFull> {
+        if self.is_full() {
+            Err(BufferFull)
+        } else {
+            self.pending = Some(cmd);
+            Ok(())
+        }
+    }
+
+    pub fn take(&mut self) -> Option<AtCommand> {
+        self.pending.take()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_one_command() {
+        let mut queue = AtCommandQueue::new();
+        assert!(queue.is_empty());
+
+        queue.put(AtCommand::new(0xED, Some(0x04))).unwrap();
+        assert!(queue.is_full());
+        assert!(queue.put(AtCommand::new(0xF3, Some(0x20))).is_err());
+
+        let cmd = queue.take().unwrap();
+        assert_eq!((cmd.cmd, cmd.arg), (0xED, Some(0x04)));
+        assert!(queue.take().is_none());
+
+        queue.put(AtCommand::new(0xF3, Some(0x20))).unwrap();
+        queue.flush();
+        assert!(queue.is_empty());
+    }
+}
@@ -1,21 +1,186 @@
+#[cfg(target_arch = "msp430")]
 use msp430g2211;
 
-macro_rules! set_bits_with_mask {
-    ($r:ident, $w:ident, $m:expr) => { $w.bits($r.bits() | $m) };
+#[cfg(not(target_arch = "msp430"))]
+use core::cell::Cell;
+
+// The Port 1 registers, as this module uses them. On the MSP430 that's PORT_1_2 itself; the
+// host test build gets PortModel (below) in its place, so the pin logic can be checked
+// without hardware.
+#[cfg(target_arch = "msp430")]
+pub type PortRegs = msp430g2211::PORT_1_2;
+#[cfg(not(target_arch = "msp430"))]
+pub type PortRegs = PortModel;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Reg {
+    In,
+    Out,
+    Dir,
+    Ifg,
+    Ies,
+    Ie,
+    Ren,
+}
+
+pub trait Port {
+    fn read(&self, reg : Reg) -> u8;
+    fn write(&self, reg : Reg, bits : u8) -> ();
+
+    fn set_bits(&self, reg : Reg, mask : u8) -> () {
+        let bits : u8 = self.read(reg);
+        self.write(reg, bits | mask);
+    }
+
+    fn clear_bits(&self, reg : Reg, mask : u8) -> () {
+        let bits : u8 = self.read(reg);
+        self.write(reg, bits & !mask);
+    }
+
+    fn toggle_bits(&self, reg : Reg, mask : u8) -> () {
+        let bits : u8 = self.read(reg);
+        self.write(reg, bits ^ mask);
+    }
+}
+
+#[cfg(target_arch = "msp430")]
+impl Port for msp430g2211::PORT_1_2 {
+    fn read(&self, reg : Reg) -> u8 {
+        match reg {
+            Reg::In => { self.p1in.read().bits() },
+            Reg::Out => { self.p1out.read().bits() },
+            Reg::Dir => { self.p1dir.read().bits() },
+            Reg::Ifg => { self.p1ifg.read().bits() },
+            Reg::Ies => { self.p1ies.read().bits() },
+            Reg::Ie => { self.p1ie.read().bits() },
+            Reg::Ren => { self.p1ren.read().bits() },
+        }
+    }
+
+    fn write(&self, reg : Reg, bits : u8) -> () {
+        match reg {
+            Reg::In => { }, // Read-only.
+            Reg::Out => { self.p1out.write(|w| w.bits(bits)); },
+            Reg::Dir => { self.p1dir.write(|w| w.bits(bits)); },
+            Reg::Ifg => { self.p1ifg.write(|w| w.bits(bits)); },
+            Reg::Ies => { self.p1ies.write(|w| w.bits(bits)); },
+            Reg::Ie => { self.p1ie.write(|w| w.bits(bits)); },
+            Reg::Ren => { self.p1ren.write(|w| w.bits(bits)); },
+        }
+    }
+}
+
+// Stand-in for Port 1 in the host test build. Reads of P1IN see whatever the test put in
+// pins_in. Besides the registers, it remembers every pin that was ever an output with its
+// P1OUT bit high at the same time- on an open-drain line, a short against whoever is
+// pulling it low.
+#[cfg(not(target_arch = "msp430"))]
+pub struct PortModel {
+    regs : [Cell<u8>; 7],
+    pub pins_in : Cell<u8>,
+    pub driven_high : Cell<u8>,
+}
+
+#[cfg(not(target_arch = "msp430"))]
+impl PortModel {
+    pub fn new() -> PortModel {
+        PortModel {
+            regs : [Cell::new(0), Cell::new(0), Cell::new(0), Cell::new(0), Cell::new(0),
+                    Cell::new(0), Cell::new(0)],
+            pins_in : Cell::new(0),
+            driven_high : Cell::new(0),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "msp430"))]
+impl Port for PortModel {
+    fn read(&self, reg : Reg) -> u8 {
+        match reg {
+            Reg::In => { self.pins_in.get() },
+            _ => { self.regs[reg as usize].get() },
+        }
+    }
+
+    fn write(&self, reg : Reg, bits : u8) -> () {
+        if reg != Reg::In {
+            self.regs[reg as usize].set(bits);
+        }
+
+        let driven : u8 = self.read(Reg::Dir) & self.read(Reg::Out);
+        self.driven_high.set(self.driven_high.get() | driven);
+    }
 }
 
-macro_rules! clear_bits_with_mask {
-    ($r:ident, $w:ident, $m:expr) => { $w.bits($r.bits() & !$m) };
+// Set if the board drives XT data through an inverting stage (e.g. an open-collector
+// transistor), so that pin low means line high. Only data is covered: xt_clk is also what
+// xt_sense watches for host resets, and inverting it would flip that edge too. A board
+// that inverts both lines needs the sense interrupt edge (P1IES) changed as well. The
+// host-busy check in send_byte_to_pc reads the pin, so it assumes the line is also read
+// back through a non-inverting path.
+pub const XT_DATA_INVERT : bool = false;
+
+// Which PORT_1_2 bit each signal is on. DEFAULT_PIN_MAP is the wiring of the one board
+// that exists; a second converter on the same chip would need its own map (and the
+// interrupt handler would have to tell the two apart, which it doesn't yet).
+#[derive(Clone, Copy)]
+pub struct PinMap {
+    pub at_clk : u8,
+    pub at_data : u8,
+    pub xt_clk : u8,
+    pub xt_data : u8,
+    pub xt_sense : u8, // Tied to xt_clk on the board; used to spot host resets.
+    pub debug_tx : u8,
+    pub heartbeat : u8,
+    pub test_jumper : u8,
 }
 
-pub const AT_CLK : u8 = (1 << 0);
+// P1.0 AT clock, P1.1 XT sense, P1.2 XT clock, P1.3 XT data, P1.4 AT data, P1.5 to P1.7
+// spare.
+pub const DEFAULT_PIN_MAP : PinMap = PinMap {
+    at_clk : 0,
+    at_data : 4,
+    xt_clk : 2,
+    xt_data : 3,
+    xt_sense : 1,
+    debug_tx : 5,
+    heartbeat : 6,
+    test_jumper : 7,
+};
+
+// All four bus lines as read from a single P1IN access.
+#[derive(Clone, Copy)]
+pub struct PinSnapshot {
+    pub at_clk : bool,
+    pub at_data : bool,
+    pub xt_clk : bool,
+    pub xt_data : bool,
+}
 
+// Both AT lines are open-drain, with pull-ups on the keyboard side: either end may pull a
+// line low, and nobody drives it high. A line we want high is released (made an input)
+// and floats up; driving it high while the keyboard pulls it low would short the two
+// drivers. So at_clk and at_data only ever become outputs with their P1OUT bit already
+// low (see at_line_low), and everything here that means "high" on the AT side is
+// at_line_release or mk_in. Pin::set on an AT pin only prepares P1OUT for a later
+// release; it never drives anything. The XT side is push-pull (xt_out) while we send,
+// and released otherwise.
 pub struct KeyboardPins {
     pub at_clk : Pin,
     pub at_data : Pin,
     pub xt_clk : Pin,
     pub xt_data : Pin,
     pub xt_sense : Pin,
+    // Spare pin, only driven by the debug-uart feature.
+    #[cfg_attr(not(feature = "debug-uart"), allow(dead_code))]
+    pub debug_tx : Pin,
+    // Other spare pin, only driven by the heartbeat feature (which also blinks it when no
+    // keyboard answers at startup). boot-selfcheck borrows it to report a bad clock.
+    #[cfg_attr(not(any(feature = "heartbeat", feature = "boot-selfcheck")), allow(dead_code))]
+    pub heartbeat : Pin,
+    // Last spare pin, only read by the wiring-test feature.
+    #[cfg_attr(not(feature = "wiring-test"), allow(dead_code))]
+    pub test_jumper : Pin,
     // was_initialized : bool
 }
 
@@ -23,12 +188,19 @@ impl KeyboardPins {
     // Safe as long as only one copy exists in memory (and it doesn't make sense for two copies to
     // exist); P1DIR can only be accessed from within this module, and never from an interrupt.
     pub const fn new() -> KeyboardPins {
+        KeyboardPins::from_map(DEFAULT_PIN_MAP)
+    }
+
+    pub const fn from_map(map : PinMap) -> KeyboardPins {
         KeyboardPins {
-            at_clk : Pin::new(0),
-            at_data : Pin::new(4),
-            xt_clk : Pin::new(2),
-            xt_data : Pin::new(3),
-            xt_sense : Pin::new(1)
+            at_clk : Pin::new(map.at_clk),
+            at_data : Pin::new(map.at_data),
+            xt_clk : Pin::new(map.xt_clk),
+            xt_data : Pin::new(map.xt_data),
+            xt_sense : Pin::new(map.xt_sense),
+            debug_tx : Pin::new(map.debug_tx),
+            heartbeat : Pin::new(map.heartbeat),
+            test_jumper : Pin::new(map.test_jumper)
         }
     }
 
@@ -36,76 +208,182 @@ impl KeyboardPins {
     // initialization before the only interrupts that touches these registers is enabled.
     // Option 1: Possible to make fully safe using was_initialized?
     // Pitfall 1: Does globally enable
-    pub fn idle(&self, p : &msp430g2211::PORT_1_2)  -> () {
-        p.p1dir.write(|w| w.bits(0x00));
-        p.p1ifg.modify(|r, w| clear_bits_with_mask!(r, w, AT_CLK));
-        p.p1ies.modify(|r, w| set_bits_with_mask!(r, w, AT_CLK));
-        p.p1ie.modify(|r, w| set_bits_with_mask!(r, w, AT_CLK));
-        // p.p1ifg.modify(|r, w| clear_bits_with_mask!(r, w, self.at_clk.bitmask()));
-        // p.p1ies.modify(|r, w| set_bits_with_mask!(r, w, self.at_clk.bitmask()));
-        // p.p1ie.modify(|r, w| set_bits_with_mask!(r, w, self.at_clk.bitmask()));
+    pub fn idle(&self, p : &PortRegs)  -> () {
+        // Only our own pins; another set of pins on the port may already be in use.
+        let all_mask : u8 = self.at_clk.bitmask() | self.at_data.bitmask() |
+            self.xt_clk.bitmask() | self.xt_data.bitmask() | self.xt_sense.bitmask();
+        p.clear_bits(Reg::Dir, all_mask);
+        p.clear_bits(Reg::Ifg, self.at_clk.bitmask());
+        p.set_bits(Reg::Ies, self.at_clk.bitmask());
+        p.set_bits(Reg::Ie, self.at_clk.bitmask());
     }
 
-    pub fn disable_at_clk_int(&self, p : &msp430g2211::PORT_1_2) -> () {
-        p.p1ie.modify(|r, w| clear_bits_with_mask!(r, w, self.at_clk.bitmask()));
+    pub fn disable_at_clk_int(&self, p : &PortRegs) -> () {
+        p.clear_bits(Reg::Ie, self.at_clk.bitmask());
     }
 
     // Unsafe because can be used in contexts where it's assumed pin ints can't occur.
-    pub unsafe fn enable_at_clk_int(&self, p : &msp430g2211::PORT_1_2) -> () {
-        p.p1ie.modify(|r, w| set_bits_with_mask!(r, w, self.at_clk.bitmask()));
+    pub unsafe fn enable_at_clk_int(&self, p : &PortRegs) -> () {
+        p.set_bits(Reg::Ie, self.at_clk.bitmask());
+    }
+
+    pub fn clear_at_clk_int(&self, p : &PortRegs) -> () {
+        p.clear_bits(Reg::Ifg, self.at_clk.bitmask());
     }
 
-    pub fn clear_at_clk_int(&self, p : &msp430g2211::PORT_1_2) -> () {
-        p.p1ifg.modify(|r, w| clear_bits_with_mask!(r, w, self.at_clk.bitmask()));
+    // Flags are also set while the interrupt is disabled (e.g. when we drive at_clk low
+    // ourselves), so only an enabled source counts as pending.
+    pub fn at_clk_int_pending(&self, p : &PortRegs) -> bool {
+        (p.read(Reg::Ifg) & p.read(Reg::Ie) & self.at_clk.bitmask()) != 0
     }
 
-    pub fn at_idle(&self, p : &msp430g2211::PORT_1_2) -> () {
-        // XXX: Mutable borrow happens twice if we borrow port first and then call these
-        // fns?
+    // xt_sense is on the same port as everything else, so it shares the PORT1 vector with
+    // at_clk. It watches the XT clock line, which means our own XT transmissions will set
+    // its flag too. There is no PORT2 handler for it to have instead: the G2211's only
+    // Port 2 pins are P2.6/P2.7, which the board leaves as XIN/XOUT, and moving xt_sense
+    // there would mean a new board revision.
+    pub fn disable_xt_sense_int(&self, p : &PortRegs) -> () {
+        p.clear_bits(Reg::Ie, self.xt_sense.bitmask());
+    }
+
+    // Unsafe for the same reason as enable_at_clk_int.
+    pub unsafe fn enable_xt_sense_int(&self, p : &PortRegs) -> () {
+        p.set_bits(Reg::Ies, self.xt_sense.bitmask());
+        p.set_bits(Reg::Ie, self.xt_sense.bitmask());
+    }
+
+    pub fn clear_xt_sense_int(&self, p : &PortRegs) -> () {
+        p.clear_bits(Reg::Ifg, self.xt_sense.bitmask());
+    }
+
+    pub fn xt_sense_int_pending(&self, p : &PortRegs) -> bool {
+        (p.read(Reg::Ifg) & p.read(Reg::Ie) & self.xt_sense.bitmask()) != 0
+    }
+
+    pub fn at_idle(&self, p : &PortRegs) -> () {
+        // Release first: a line we were holding low is still an output until then, and
+        // setting its P1OUT bit before would drive it high.
+        let at_mask : u8 = self.at_clk.bitmask() | self.at_data.bitmask();
+        p.clear_bits(Reg::Dir, at_mask);
         self.at_clk.set(p);
         self.at_data.set(p);
-        {
+    }
 
-            let at_mask : u8 = self.at_clk.bitmask() | self.at_data.bitmask();
-            p.p1dir.modify(|r, w| clear_bits_with_mask!(r, w, at_mask));
+    // Clock held low, data left released.
+    pub fn at_inhibit(&self, p : &PortRegs) -> () {
+        at_line_low(&self.at_clk, p);
+        at_line_release(&self.at_data, p);
+    }
+
+    // Host-to-device handshake, in two halves so the caller can time the gaps. Once
+    // at_inhibit() has held the clock low for at least 100us, pull data low (the start bit).
+    pub fn at_request_to_send(&self, p : &PortRegs) -> () {
+        at_line_low(&self.at_data, p);
+    }
+
+    // One data bit of a host-to-device frame, from the interrupt.
+    pub fn at_data_write(&self, p : &PortRegs, bit : bool) -> () {
+        if bit {
+            at_line_release(&self.at_data, p);
+        } else {
+            at_line_low(&self.at_data, p);
         }
     }
 
-    pub fn at_inhibit(&self, p : &msp430g2211::PORT_1_2) -> () {
-        // XXX: Mutable borrow happens twice if we borrow port first and then call these
-        // fns?
-        self.at_clk.unset(p);
-        self.at_data.set(p);
-        {
+    // Then let go of the clock; the keyboard starts clocking the rest of the frame in, one
+    // interrupt per bit. Unsafe for the same reason as enable_at_clk_int.
+    pub unsafe fn begin_host_transmission(&self, p : &PortRegs) -> () {
+        at_line_release(&self.at_clk, p);
+        self.clear_at_clk_int(p);
+        self.enable_at_clk_int(p);
+    }
+
+    // Nothing in the firmware sends this way any more (see at_request_to_send); kept for
+    // the line-driving test, which checks every way the AT lines can be let go.
+    #[cfg(test)]
+    pub fn at_send(&self, p : &PortRegs) -> () {
+        at_line_release(&self.at_clk, p);
+        at_line_release(&self.at_data, p);
+    }
 
-            let at_mask : u8 = self.at_clk.bitmask() | self.at_data.bitmask();
-            p.p1dir.modify(|r, w| set_bits_with_mask!(r, w, at_mask));
+    pub fn snapshot(&self, p : &PortRegs) -> PinSnapshot {
+        let p1in : u8 = p.read(Reg::In);
+        PinSnapshot {
+            at_clk : self.at_clk.is_set_in(p1in),
+            at_data : self.at_data.is_set_in(p1in),
+            xt_clk : self.xt_clk.is_set_in(p1in),
+            xt_data : self.xt_data.is_set_in(p1in),
         }
     }
 
-    #[allow(dead_code)]
-    pub fn at_send(&self, p : &msp430g2211::PORT_1_2) -> () {
-        self.at_clk.set(p);
-        self.at_data.set(p);
-        self.at_clk.mk_in(p);
-        self.at_data.mk_out(p);
+    // Both AT lines released and high- keyboard isn't in the middle of a frame.
+    pub fn at_bus_idle(&self, p : &PortRegs) -> bool {
+        let lines = self.snapshot(p);
+        lines.at_clk && lines.at_data
+    }
+
+    // Let go of both buses and stop listening to either, whatever state they were left in.
+    // For the panic path, where nothing else is going to run afterwards.
+    pub fn force_idle_all(&self, p : &PortRegs) -> () {
+        self.disable_at_clk_int(p);
+        self.disable_xt_sense_int(p);
+        self.at_idle(p);
+        self.xt_in(p);
+    }
+
+    // wiring-test: the jumper shorts test_jumper to ground. Pull the pin up first, and read
+    // it a while later with test_jumper_fitted(), once the pull-up (tens of kilohms
+    // against the pin's capacitance) has had time to bring an open pin high.
+    #[cfg(feature = "wiring-test")]
+    pub fn pull_up_test_jumper(&self, p : &PortRegs) -> () {
+        self.test_jumper.mk_in(p);
+        self.test_jumper.set(p);
+        p.set_bits(Reg::Ren, self.test_jumper.bitmask());
+    }
+
+    #[cfg(feature = "wiring-test")]
+    pub fn test_jumper_fitted(&self, p : &PortRegs) -> bool {
+        self.test_jumper.is_unset(p)
     }
 
     // Why in japaric's closures access to the pins for an actual write aren't wrapped in unsafe?
-    pub fn xt_out(&self, p : &msp430g2211::PORT_1_2) -> () {
+    pub fn xt_out(&self, p : &PortRegs) -> () {
         let xt_mask : u8 = self.xt_clk.bitmask() | self.xt_data.bitmask();
-        p.p1out.modify(|r, w| set_bits_with_mask!(r, w, xt_mask));
-        p.p1dir.modify(|r, w| set_bits_with_mask!(r, w, xt_mask));
+        self.xt_clk.set(p);
+        self.xt_data_write(p, true);
+        p.set_bits(Reg::Dir, xt_mask);
     }
 
-    pub fn xt_in(&self, p : &msp430g2211::PORT_1_2) -> () {
+    pub fn xt_in(&self, p : &PortRegs) -> () {
         let xt_mask : u8 = self.xt_clk.bitmask() | self.xt_data.bitmask();
-        p.p1out.modify(|r, w| set_bits_with_mask!(r, w, self.xt_data.bitmask()));
-        p.p1dir.modify(|r, w| clear_bits_with_mask!(r, w, xt_mask));
+        self.xt_data_write(p, true);
+        p.clear_bits(Reg::Dir, xt_mask);
+    }
+
+    // Put a bit on the XT data line, as the host will see it.
+    pub fn xt_data_write(&self, p : &PortRegs, bit : bool) -> () {
+        if bit != XT_DATA_INVERT {
+            self.xt_data.set(p);
+        } else {
+            self.xt_data.unset(p);
+        }
     }
 }
 
 
+// The only two things an AT line is ever made to do. The order matters for the low
+// half: P1OUT goes low before the pin becomes an output, so it never drives high, even
+// for an instruction, whatever at_idle or anyone else left in P1OUT.
+fn at_line_low(pin : &Pin, p : &PortRegs) -> () {
+    pin.unset(p);
+    pin.mk_out(p);
+}
+
+fn at_line_release(pin : &Pin, p : &PortRegs) -> () {
+    pin.mk_in(p);
+}
+
+
 pub struct Pin {
     loc : u8
 }
@@ -116,33 +394,159 @@ impl Pin {
     }
 
     fn bitmask(&self) -> u8 {
-        (1 << self.loc)
+        1 << self.loc
     }
 
-    pub fn set(&self, p : &msp430g2211::PORT_1_2) -> () {
-        p.p1out.modify(|r, w| set_bits_with_mask!(r, w, self.bitmask()));
+    pub fn set(&self, p : &PortRegs) -> () {
+        p.set_bits(Reg::Out, self.bitmask());
     }
 
-    pub fn unset(&self, p : &msp430g2211::PORT_1_2) -> () {
-        p.p1out.modify(|r, w| clear_bits_with_mask!(r, w, self.bitmask()));
+    pub fn unset(&self, p : &PortRegs) -> () {
+        p.clear_bits(Reg::Out, self.bitmask());
     }
 
-    pub fn mk_in(&self, p : &msp430g2211::PORT_1_2) -> () {
-        p.p1dir.modify(|r, w| clear_bits_with_mask!(r, w, self.bitmask()));
+    pub fn mk_in(&self, p : &PortRegs) -> () {
+        p.clear_bits(Reg::Dir, self.bitmask());
+    }
+
+    #[allow(dead_code)]
+    pub fn toggle(&self, p : &PortRegs) -> () {
+        p.toggle_bits(Reg::Out, self.bitmask());
     }
 
     #[allow(dead_code)]
-    pub fn mk_out(&self, p : &msp430g2211::PORT_1_2) -> () {
-        p.p1dir.modify(|r, w| set_bits_with_mask!(r, w, self.bitmask()));
+    pub fn mk_out(&self, p : &PortRegs) -> () {
+        p.set_bits(Reg::Dir, self.bitmask());
     }
 
 
     // No side effects from reading pins- these fcns are safe.
-    pub fn is_set(&self, p: &msp430g2211::PORT_1_2) ->  bool {
-        (p.p1in.read().bits() & self.bitmask()) != 0
+    pub fn is_set(&self, p : &PortRegs) ->  bool {
+        (p.read(Reg::In) & self.bitmask()) != 0
+    }
+
+    pub fn is_unset(&self, p : &PortRegs) -> bool {
+        (p.read(Reg::In) & self.bitmask()) == 0
+    }
+
+    // Same as is_set, but on a previously-read P1IN value.
+    fn is_set_in(&self, p1in : u8) -> bool {
+        (p1in & self.bitmask()) != 0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AT_CLK : u8 = 1 << DEFAULT_PIN_MAP.at_clk;
+    const AT_DATA : u8 = 1 << DEFAULT_PIN_MAP.at_data;
+    const AT_LINES : u8 = AT_CLK | AT_DATA;
+
+    fn at_lines_released(p : &PortModel) -> bool {
+        p.read(Reg::Dir) & AT_LINES == 0
+    }
+
+    #[test]
+    fn at_idle_releases_both_lines() {
+        let pins = KeyboardPins::new();
+        let p = PortModel::new();
+        p.write(Reg::Dir, 0xff);
+
+        pins.at_idle(&p);
+        assert!(at_lines_released(&p));
+        // Released with P1OUT high: the same as open-drain high, once it's an input.
+        assert_eq!(p.read(Reg::Out) & AT_LINES, AT_LINES);
     }
 
-    pub fn is_unset(&self, p: &msp430g2211::PORT_1_2) -> bool {
-        (p.p1in.read().bits() & self.bitmask()) == 0
+    #[test]
+    fn at_inhibit_pulls_clock_low_and_releases_data() {
+        let pins = KeyboardPins::new();
+        let p = PortModel::new();
+
+        pins.at_idle(&p);
+        pins.at_inhibit(&p);
+        assert_eq!(p.read(Reg::Dir) & AT_LINES, AT_CLK);
+        assert_eq!(p.read(Reg::Out) & AT_CLK, 0);
+    }
+
+    // at_idle leaves P1OUT high on both AT pins, which is the case where turning a pin into
+    // an output before clearing P1OUT would drive the line high.
+    #[test]
+    fn at_lines_never_driven_high() {
+        let pins = KeyboardPins::new();
+        let p = PortModel::new();
+
+        pins.at_idle(&p);
+        pins.at_inhibit(&p);
+        pins.at_request_to_send(&p);
+        unsafe { pins.begin_host_transmission(&p); }
+        for bit in [false, true, true, false, true].iter() {
+            pins.at_data_write(&p, *bit);
+        }
+        pins.at_idle(&p);
+        pins.at_inhibit(&p);
+        pins.at_send(&p);
+
+        assert_eq!(p.driven_high.get() & AT_LINES, 0);
+        assert!(at_lines_released(&p));
+    }
+
+    #[test]
+    fn snapshot_decomposes_p1in() {
+        let pins = KeyboardPins::new();
+        let p = PortModel::new();
+
+        // All four lines high, plus xt_sense and the spare pins, which must be ignored.
+        p.pins_in.set(0xff);
+        let all = pins.snapshot(&p);
+        assert!(all.at_clk && all.at_data && all.xt_clk && all.xt_data);
+
+        // P1.0 AT clock, P1.2 XT clock, P1.3 XT data, P1.4 AT data.
+        p.pins_in.set(0b0000_0001);
+        let s = pins.snapshot(&p);
+        assert!(s.at_clk && !s.at_data && !s.xt_clk && !s.xt_data);
+
+        p.pins_in.set(0b0001_0000);
+        let s = pins.snapshot(&p);
+        assert!(!s.at_clk && s.at_data && !s.xt_clk && !s.xt_data);
+
+        p.pins_in.set(0b0000_0100);
+        let s = pins.snapshot(&p);
+        assert!(!s.at_clk && !s.at_data && s.xt_clk && !s.xt_data);
+
+        p.pins_in.set(0b0000_1000);
+        let s = pins.snapshot(&p);
+        assert!(!s.at_clk && !s.at_data && !s.xt_clk && s.xt_data);
+
+        p.pins_in.set(0b1110_0010);
+        let none = pins.snapshot(&p);
+        assert!(!none.at_clk && !none.at_data && !none.xt_clk && !none.xt_data);
+    }
+
+    #[test]
+    fn at_bus_idle_needs_both_lines_high() {
+        let pins = KeyboardPins::new();
+        let p = PortModel::new();
+
+        p.pins_in.set(AT_LINES);
+        assert!(pins.at_bus_idle(&p));
+        p.pins_in.set(AT_CLK);
+        assert!(!pins.at_bus_idle(&p));
+        p.pins_in.set(AT_DATA);
+        assert!(!pins.at_bus_idle(&p));
+    }
+
+    #[test]
+    fn at_data_high_is_released() {
+        let pins = KeyboardPins::new();
+        let p = PortModel::new();
+
+        pins.at_data_write(&p, false);
+        assert_eq!(p.read(Reg::Dir) & AT_DATA, AT_DATA);
+        assert_eq!(p.read(Reg::Out) & AT_DATA, 0);
+        pins.at_data_write(&p, true);
+        assert_eq!(p.read(Reg::Dir) & AT_DATA, 0);
     }
 }
@@ -0,0 +1,19 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AtError {
+    BufferFull, // KEY_OUT was still busy with a previous byte.
+    ClockTimeout, // Keyboard stopped clocking (or never started).
+    NoAck, // Keyboard kept asking for a resend, or replied with something other than ACK.
+    ParityError, // Keyboard's reply was corrupt.
+    InvalidArgument, // Refused to send a malformed command.
+    SelfTestFailed, // Keyboard reported 0xFC/0xFD after a reset.
+    NoKeyboard, // Nothing clocked in the reset command; most likely nothing is plugged in.
+}
+
+// The frame didn't fit and was not stored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BufferFull;
+
+// The host held XT clock or data low for longer than XT_HOST_BUSY_TIMEOUT_US; nothing was
+// sent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HostBusy;
@@ -1,15 +1,90 @@
-pub struct KeycodeBuffer {
+use bit_reverse::BitwiseReverse;
+
+use error::BufferFull;
+
+// AT sends the data bits LSb first. Flip this if porting to a bus (or a shift direction)
+// that delivers them the other way around; nothing outside KeyIn cares.
+pub const AT_LSB_FIRST : bool = true;
+
+// Frames are shifted in MSb first, so the start bit ends up in bit 10, the data bits
+// (LSb first on the wire) in bits 9-2, the parity bit in bit 1, and the stop bit in bit 0.
+pub fn check_parity(raw : u16) -> bool {
+    let mut data_and_parity : u16 = (raw >> 1) & 0x01FF;
+    let mut num_ones : u8 = 0;
+
+    for _ in 0..9 {
+        num_ones = num_ones + ((data_and_parity & 0x01) as u8);
+        data_and_parity = data_and_parity >> 1;
+    }
+
+    // Odd parity- an all-zero data byte must come with a parity bit of 1.
+    num_ones % 2 == 1
+}
+
+// The array behind a buffer. The nightly this crate builds with has no const generics,
+// so a buffer is sized by its array type instead, and only the sizes listed below exist.
+pub trait Slots : Copy {
+    type Item : Copy;
+    const LEN : usize;
+    const EMPTY : Self;
+
+    fn slots(&self) -> &[Self::Item];
+    fn slots_mut(&mut self) -> &mut [Self::Item];
+}
+
+macro_rules! slots {
+    ($t:ty; $($n:expr),*) => {
+        $(
+            impl Slots for [$t; $n] {
+                type Item = $t;
+                const LEN : usize = $n;
+                const EMPTY : [$t; $n] = [0; $n];
+
+                fn slots(&self) -> &[$t] {
+                    self
+                }
+
+                fn slots_mut(&mut self) -> &mut [$t] {
+                    self
+                }
+            }
+        )*
+    }
+}
+
+slots!(u16; 4, 8, 16, 32);
+slots!(u8; 4, 5, 6, 8, 16);
+
+// Ring buffer of raw frames. One slot is always left open to tell full from empty, so
+// N slots hold N - 1 frames.
+// use-timer: frames are single keycodes, and put() keeps the buffer's clock (see
+// set_clock) in the upper byte of the slot alongside each one, for flush_older_than().
+// take() and peek() hand back the keycode alone.
+pub struct KeycodeBuffer<S : Slots<Item = u16>> {
     head : u8,
     tail : u8,
-    contents : [u16; 16],
+    dropped : u8, // Frames refused for want of room, saturating. flush() leaves it alone.
+    #[cfg(feature = "use-timer")]
+    now : u8, // Ticks, wrapping; whatever the caller last said it was.
+    contents : S,
 }
 
-impl KeycodeBuffer {
-    pub const fn new() -> KeycodeBuffer {
+#[cfg(feature = "use-timer")]
+const KEY_MASK : u16 = 0x00ff;
+#[cfg(not(feature = "use-timer"))]
+const KEY_MASK : u16 = 0xffff;
+
+pub type DefaultBuffer = KeycodeBuffer<[u16; 16]>;
+
+impl<S : Slots<Item = u16>> KeycodeBuffer<S> {
+    pub const fn new() -> KeycodeBuffer<S> {
         KeycodeBuffer {
             head : 0,
             tail : 0,
-            contents : [0; 16],
+            dropped : 0,
+            #[cfg(feature = "use-timer")]
+            now : 0,
+            contents : S::EMPTY,
         }
     }
 
@@ -19,25 +94,162 @@ impl KeycodeBuffer {
     }
 
     pub fn is_empty(&self) -> bool {
-        (self.head - self.tail == 0)
+        self.head == self.tail
     }
 
-    pub fn put(&mut self, in_key : u16) -> () {
-        // TODO: A full buffer is an abnormal condition worth a panic/reset.
+    pub fn is_full(&self) -> bool {
+        Self::next(self.tail) == self.head
+    }
+
+    // Frames currently stored. Adding N first keeps this right once tail has wrapped
+    // around behind head.
+    pub fn len(&self) -> usize {
+        (self.tail as usize + S::LEN - self.head as usize) % S::LEN
+    }
 
-        self.contents[self.tail as usize] = in_key;
-        self.tail = (self.tail + 1) % 16;
+    // Most frames the buffer can hold at once; one less than N (see above).
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> usize {
+        S::LEN - 1
+    }
+
+    pub fn put(&mut self, in_key : u16) -> Result<(), BufferFull> {
+        if self.is_full() {
+            self.dropped = self.dropped.saturating_add(1);
+            Err(BufferFull)
+        } else {
+            self.contents.slots_mut()[self.tail as usize] = in_key | self.stamp();
+            self.tail = Self::next(self.tail);
+            Ok(())
+        }
     }
 
     pub fn take(&mut self) -> Option<u16> {
         if self.is_empty() {
             None
         } else {
-            let out_key : u16 = self.contents[self.head as usize];
-            self.head = (self.head + 1) % 16;
+            let out_key : u16 = self.contents.slots()[self.head as usize] & KEY_MASK;
+            self.head = Self::next(self.head);
             Some(out_key)
         }
     }
+
+    // How many frames put() has turned away so far, to tell a buffer that's too small
+    // from a keyboard that's garbling frames.
+    pub fn dropped(&self) -> u8 {
+        self.dropped
+    }
+
+    // Look at the oldest frame without consuming it, e.g. to check for a prefix byte.
+    pub fn peek(&self) -> Option<u16> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.contents.slots()[self.head as usize] & KEY_MASK)
+        }
+    }
+
+    // use-timer: what put() stamps frames with from now on, and what flush_older_than()
+    // measures their age against.
+    #[cfg(feature = "use-timer")]
+    pub fn set_clock(&mut self, now : u8) -> () {
+        self.now = now;
+    }
+
+    // use-timer: drop frames stamped more than ticks before now, oldest first. Frames are
+    // stamped in the order they arrive, so the first one young enough to keep ends it.
+    // Ages are taken modulo 256 ticks, so anything older than that may come out young.
+    #[cfg(feature = "use-timer")]
+    pub fn flush_older_than(&mut self, ticks : u8) -> () {
+        while !self.is_empty() {
+            let stamp : u8 = (self.contents.slots()[self.head as usize] >> 8) as u8;
+            if self.now.wrapping_sub(stamp) <= ticks {
+                break;
+            }
+            self.head = Self::next(self.head);
+        }
+    }
+
+    #[cfg(feature = "use-timer")]
+    fn stamp(&self) -> u16 {
+        (self.now as u16) << 8
+    }
+
+    #[cfg(not(feature = "use-timer"))]
+    fn stamp(&self) -> u16 {
+        0
+    }
+
+    fn next(idx : u8) -> u8 {
+        ((idx as usize + 1) % S::LEN) as u8
+    }
+}
+
+
+// Bytes on their way to the PC, oldest first. Same layout as KeycodeBuffer, a byte wide:
+// the XT side only ever sends plain data bytes, and RAM is tight.
+pub struct ByteBuffer<S : Slots<Item = u8>> {
+    head : u8,
+    tail : u8,
+    contents : S,
+}
+
+// Holds 4 bytes: two keys, or one extended key.
+pub type DefaultOutBuffer = ByteBuffer<[u8; 5]>;
+
+impl<S : Slots<Item = u8>> ByteBuffer<S> {
+    pub const fn new() -> ByteBuffer<S> {
+        ByteBuffer {
+            head : 0,
+            tail : 0,
+            contents : S::EMPTY,
+        }
+    }
+
+    pub fn flush(&mut self) -> () {
+        self.tail = 0;
+        self.head = 0;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head == self.tail
+    }
+
+    pub fn is_full(&self) -> bool {
+        Self::next(self.tail) == self.head
+    }
+
+    pub fn put(&mut self, byte : u8) -> Result<(), BufferFull> {
+        if self.is_full() {
+            Err(BufferFull)
+        } else {
+            self.contents.slots_mut()[self.tail as usize] = byte;
+            self.tail = Self::next(self.tail);
+            Ok(())
+        }
+    }
+
+    pub fn take(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            None
+        } else {
+            let out_byte : u8 = self.contents.slots()[self.head as usize];
+            self.head = Self::next(self.head);
+            Some(out_byte)
+        }
+    }
+
+    pub fn peek(&self) -> Option<u8> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.contents.slots()[self.head as usize])
+        }
+    }
+
+    fn next(idx : u8) -> u8 {
+        ((idx as usize + 1) % S::LEN) as u8
+    }
 }
 
 
@@ -59,13 +271,23 @@ impl KeyIn {
         self.pos >= 11
     }
 
+    // Empty, ready for the next start bit: no bits counted, none kept. take() and
+    // take_raw() leave it this way too.
     pub fn clear(&mut self) {
         self.pos = 0;
         self.contents = 0;
     }
 
+    // Start bit low, stop bit high, odd parity. Only meaningful once is_full().
+    pub fn is_valid_frame(&self) -> bool {
+        let start_ok : bool = (self.contents & (1 << 10)) == 0;
+        let stop_ok : bool = (self.contents & (1 << 0)) != 0;
+        start_ok && stop_ok && check_parity(self.contents)
+    }
+
     pub fn shift_in(&mut self, bit : bool) -> () {
-        // TODO: A nonzero start value (when self.pos == 0) is a runtime invariant violation.
+        // pos == 0 always comes with contents == 0, so the frame is exactly the last 11
+        // bits shifted in and nothing is left over above it.
         let cast_bit : u16 = if bit {
                 1
             } else {
@@ -75,70 +297,556 @@ impl KeyIn {
         self.pos = self.pos + 1;
     }
 
-    pub fn take(&mut self) -> Option<u16> {
+    // Returns the data byte in its natural bit order. Check is_valid_frame() first; the
+    // framing bits are discarded here.
+    #[allow(dead_code)]
+    pub fn take(&mut self) -> Option<u8> {
+        self.take_raw().map(|(data, _)| data)
+    }
+
+    // Same as take(), plus the 11-bit frame exactly as it was shifted in, for when the
+    // decoded byte doesn't tell the whole story.
+    pub fn take_raw(&mut self) -> Option<(u8, u16)> {
         if !self.is_full() {
             None
         } else {
-            self.pos = 0;
-            Some(self.contents)
+            let raw : u16 = self.contents;
+            self.clear();
+            Some((frame_data(raw, AT_LSB_FIRST), raw))
         }
     }
 }
 
+// The data byte of a frame shifted in as KeyIn does, whichever order it came in.
+fn frame_data(raw : u16, lsb_first : bool) -> u8 {
+    let data : u8 = ((raw >> 2) & 0xFF) as u8;
+    if lsb_first {
+        data.swap_bits()
+    } else {
+        data
+    }
+}
+
 
+// One frame being shifted out to the keyboard. A command and its argument can't be queued
+// here back to back: the keyboard ACKs and answers each byte on its own, and needs a fresh
+// request-to-send before the next, so the idle context sends them one at a time (see
+// send_at_command).
 #[derive(Clone, Copy)]
 pub struct KeyOut {
     pos : u8,
     contents : u16,
+    // Set when the stop bit goes out, so the one clock after it can be told apart from a
+    // stray clock while nothing is being sent. Fits in the padding after pos.
+    ack_due : bool,
 }
 
+// Start, Data 0-7, Parity, and Stop. The start bit is driven by the idle context as part of
+// keyboard negotiation; the interrupt shifts out the rest.
+const OUT_FRAME_BITS : u8 = 11;
+
 impl KeyOut {
     pub const fn new() -> KeyOut {
         KeyOut {
-            pos : 10,
+            pos : OUT_FRAME_BITS,
             contents : 0,
+            ack_due : false,
         }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.pos > 9 // Data 0-7, Parity, and Stop. Start bit has to be handled specially b/c
-                     // it's part of keyboard negotiation.
+        self.remaining_bits() == 0
+    }
+
+    pub fn remaining_bits(&self) -> u8 {
+        OUT_FRAME_BITS - self.pos
+    }
+
+    // Number of bits already shifted out, including the start bit.
+    #[allow(dead_code)]
+    pub fn bit_index(&self) -> u8 {
+        self.pos
+    }
+
+    // True if the next shift_out() is the stop bit.
+    pub fn is_in_stop_bit(&self) -> bool {
+        self.pos == OUT_FRAME_BITS - 1
     }
 
+    // Drops the frame, whether sent or not, along with any ACK still expected for it.
     pub fn clear(&mut self) {
-        self.pos = 10;
+        self.pos = OUT_FRAME_BITS;
         self.contents = 0;
+        self.ack_due = false;
     }
 
-    pub fn shift_out(&mut self) -> bool {
-        // TODO: A nonzero start value (when self.pos == 0) is a runtime invariant violation.
+    // True exactly once per frame, on the first call after the stop bit went out.
+    pub fn take_ack_clock(&mut self) -> bool {
+        let due = self.ack_due;
+        self.ack_due = false;
+        due
+    }
+
+    // None once the whole frame, stop bit included, has gone out.
+    pub fn shift_out(&mut self) -> Option<bool> {
+        // At pos 0 this is the start bit, which has to be 0. contents only ever comes from
+        // frame(), so it always is.
+        if self.is_empty() {
+            return None;
+        }
+
         let cast_bit : bool = (self.contents & 0x01) == 1;
         self.contents = self.contents >> 1;
         self.pos = self.pos + 1;
-        cast_bit
+        self.ack_due = self.is_empty();
+        Some(cast_bit)
     }
 
-    pub fn put(&mut self, byte : u8) -> Result<(), ()> {
-        if !self.is_empty() {
-            Err(())
+    // Full while a frame is being sent or waiting for its ACK; clear() it first.
+    pub fn put(&mut self, byte : u8) -> Result<(), BufferFull> {
+        if self.is_empty() && !self.ack_due {
+            self.contents = Self::frame(byte);
+            self.pos = 0;
+            Ok(())
         } else {
-            let mut sout = byte;
-            let mut num_ones : u8 = 0;
+            Err(BufferFull)
+        }
+    }
 
-            for _ in 0..8 {
-                num_ones = num_ones + (sout & 0x01);
-                sout = sout << 1;
-            }
+    fn frame(byte : u8) -> u16 {
+        let mut sout = byte;
+        let mut num_ones : u8 = 0;
 
-            let stop_bit : u16 = 1 << 9;
-            let parity_bit : u16 = if num_ones % 2 == 0 {
-                1 << 8
-            } else {
-                0
-            };
-            self.contents = (byte as u16) | parity_bit | stop_bit;
-            self.pos = 0;
-            Ok(())
+        for _ in 0..8 {
+            num_ones = num_ones + (sout & 0x01);
+            sout = sout >> 1;
         }
+
+        let start_bit : u16 = 0;
+        let stop_bit : u16 = 1 << 10;
+        let parity_bit : u16 = if num_ones % 2 == 0 {
+            1 << 9
+        } else {
+            0
+        };
+        start_bit | ((byte as u16) << 1) | parity_bit | stop_bit
+    }
+}
+
+
+// An XT frame in the order it goes out: two start bits, 0 then 1, then the data bits LSb
+// first. There is no parity or stop bit. Only the start bits are timed differently, so
+// senders need nothing but XT_START_BITS to tell them apart.
+pub const XT_START_BITS : u8 = 2;
+pub const XT_FRAME_BITS : u8 = XT_START_BITS + 8;
+
+pub const fn xt_frame(byte : u8) -> u16 {
+    ((byte as u16) << XT_START_BITS) | 0b10
+}
+
+#[allow(dead_code)]
+const _XT_FRAME_FITS : [(); 0 - ((XT_FRAME_BITS > 16) as usize)] = [];
+
+pub struct XtFrameBits {
+    frame : u16,
+    pos : u8,
+}
+
+impl XtFrameBits {
+    pub const fn new(byte : u8) -> XtFrameBits {
+        XtFrameBits {
+            frame : xt_frame(byte),
+            pos : 0,
+        }
+    }
+}
+
+impl Iterator for XtFrameBits {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.pos >= XT_FRAME_BITS {
+            None
+        } else {
+            let bit : u8 = ((self.frame >> self.pos) & 0x01) as u8;
+            self.pos = self.pos + 1;
+            Some(bit)
+        }
+    }
+}
+
+
+// A multi-byte XT code (Pause, for now) that has to reach the PC back to back, with the
+// host given a chance to interrupt only between bytes. Sized for the longest such code.
+pub struct XtOutSequence<S : Slots<Item = u8>> {
+    len : u8,
+    bytes : S,
+}
+
+pub type DefaultSequence = XtOutSequence<[u8; 6]>;
+
+impl<S : Slots<Item = u8>> XtOutSequence<S> {
+    pub fn from_slice(seq : &[u8]) -> Result<XtOutSequence<S>, BufferFull> {
+        if seq.len() > S::LEN {
+            return Err(BufferFull);
+        }
+
+        let mut out = XtOutSequence {
+            len : seq.len() as u8,
+            bytes : S::EMPTY,
+        };
+        out.bytes.slots_mut()[..seq.len()].copy_from_slice(seq);
+        Ok(out)
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes.slots()[..self.len as usize]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An AT frame as it comes off the wire: start bit, the data bits in the order given,
+    // odd parity, stop bit.
+    fn wire_frame(byte : u8, lsb_first : bool) -> [bool; 11] {
+        let mut bits = [false; 11];
+        for i in 0..8 {
+            let n = if lsb_first { i } else { 7 - i };
+            bits[1 + i] = (byte >> n) & 0x01 == 1;
+        }
+        bits[9] = byte.count_ones() % 2 == 0;
+        bits[10] = true;
+        bits
+    }
+
+    fn shift_in_frame(key_in : &mut KeyIn, bits : &[bool; 11]) -> () {
+        for b in bits.iter() {
+            key_in.shift_in(*b);
+        }
+    }
+
+    #[test]
+    fn decodes_in_configured_order() {
+        let mut key_in = KeyIn::new();
+        shift_in_frame(&mut key_in, &wire_frame(0x1c, AT_LSB_FIRST));
+        assert!(key_in.is_valid_frame());
+        assert_eq!(key_in.take_raw().map(|(data, _)| data), Some(0x1c));
+    }
+
+    #[test]
+    fn decodes_either_order() {
+        for &lsb_first in [true, false].iter() {
+            let mut key_in = KeyIn::new();
+            shift_in_frame(&mut key_in, &wire_frame(0x1c, lsb_first));
+            let (_, raw) = key_in.take_raw().unwrap();
+            assert_eq!(frame_data(raw, lsb_first), 0x1c);
+        }
+    }
+
+    #[test]
+    fn one_bit_after_clear() {
+        let mut key_in = KeyIn::new();
+        for _ in 0..5 {
+            key_in.shift_in(true);
+        }
+
+        key_in.clear();
+        key_in.shift_in(true);
+        assert!(!key_in.is_full());
+        assert_eq!(key_in.pos, 1);
+        assert_eq!(key_in.contents, 1);
+    }
+
+    #[test]
+    fn take_raw_leaves_it_clear() {
+        let mut key_in = KeyIn::new();
+        shift_in_frame(&mut key_in, &wire_frame(0x1c, AT_LSB_FIRST));
+        assert!(key_in.take_raw().is_some());
+        assert_eq!((key_in.pos, key_in.contents), (0, 0));
+        assert_eq!(key_in.take_raw(), None);
+
+        // The next frame lines up from its own start bit.
+        shift_in_frame(&mut key_in, &wire_frame(0xf0, AT_LSB_FIRST));
+        assert!(key_in.is_valid_frame());
+        assert_eq!(key_in.take_raw().map(|(data, _)| data), Some(0xf0));
+    }
+
+    #[test]
+    fn parity() {
+        // Stop bit in bit 0, parity in bit 1, data above it: nine bits with an odd count.
+        assert!(check_parity(0b0_00000000_1_1)); // 0x00, parity 1
+        assert!(!check_parity(0b0_00000000_0_1));
+        assert!(check_parity(0b0_10000000_0_1)); // One bit set, parity 0
+        assert!(!check_parity(0b0_11000000_0_1));
+        // Start and stop bits don't count.
+        assert!(check_parity(0b1_00000000_1_0));
+    }
+
+    #[test]
+    fn frame_validity() {
+        let good = wire_frame(0x1c, AT_LSB_FIRST);
+        for i in 0..11 {
+            let mut bits = good;
+            bits[i] = !bits[i];
+
+            let mut key_in = KeyIn::new();
+            shift_in_frame(&mut key_in, &bits);
+            // Any single flipped bit breaks the start bit, a data or parity bit (parity),
+            // or the stop bit.
+            assert!(!key_in.is_valid_frame(), "bit {} flipped", i);
+        }
+
+        let mut key_in = KeyIn::new();
+        shift_in_frame(&mut key_in, &good);
+        assert!(key_in.is_valid_frame());
+    }
+
+    #[test]
+    fn peek_empty() {
+        let buf : KeycodeBuffer<[u16; 4]> = KeycodeBuffer::new();
+        assert_eq!(buf.peek(), None);
+    }
+
+    #[test]
+    fn peek_does_not_consume() {
+        let mut buf : KeycodeBuffer<[u16; 4]> = KeycodeBuffer::new();
+        buf.put(0x1c).unwrap();
+        buf.put(0xf0).unwrap();
+
+        assert_eq!(buf.peek(), Some(0x1c));
+        assert_eq!(buf.peek(), Some(0x1c));
+        assert_eq!(buf.len(), 2);
+        assert_eq!(buf.take(), Some(0x1c));
+        assert_eq!(buf.peek(), Some(0xf0));
+        assert_eq!(buf.take(), Some(0xf0));
+        assert_eq!(buf.peek(), None);
+    }
+
+    #[test]
+    fn peek_after_wrap() {
+        let mut buf : KeycodeBuffer<[u16; 4]> = KeycodeBuffer::new();
+        // Move head and tail up to the last slot, so the next frames straddle the end.
+        for k in 0..3 {
+            buf.put(k).unwrap();
+            assert_eq!(buf.take(), Some(k));
+        }
+
+        buf.put(0xe0).unwrap();
+        buf.put(0x75).unwrap();
+        assert_eq!(buf.peek(), Some(0xe0));
+        assert_eq!(buf.take(), Some(0xe0));
+        assert_eq!(buf.peek(), Some(0x75));
+        assert_eq!(buf.take(), Some(0x75));
+        assert_eq!(buf.peek(), None);
+    }
+
+    #[test]
+    fn len_counts_up_to_full() {
+        let mut buf : KeycodeBuffer<[u16; 4]> = KeycodeBuffer::new();
+        for k in 0..3 {
+            assert_eq!(buf.len(), k as usize);
+            buf.put(k).unwrap();
+        }
+
+        assert_eq!(buf.len(), 3);
+        assert_eq!(buf.len(), buf.capacity());
+        assert!(buf.is_full());
+        assert!(buf.put(3).is_err());
+        assert_eq!(buf.len(), 3);
+
+        buf.flush();
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn dropped_counts_refused_frames() {
+        let mut buf : KeycodeBuffer<[u16; 4]> = KeycodeBuffer::new();
+        for k in 0..3 {
+            buf.put(k).unwrap();
+        }
+        assert_eq!(buf.dropped(), 0);
+
+        assert!(buf.put(3).is_err());
+        assert!(buf.put(4).is_err());
+        assert_eq!(buf.dropped(), 2);
+
+        // Survives a flush, and sticks at the top rather than wrapping.
+        buf.flush();
+        assert_eq!(buf.dropped(), 2);
+        for k in 0..3 {
+            buf.put(k).unwrap();
+        }
+        for _ in 0..300 {
+            let _ = buf.put(0);
+        }
+        assert_eq!(buf.dropped(), 0xff);
+    }
+
+    #[test]
+    fn len_with_tail_behind_head() {
+        let mut buf : KeycodeBuffer<[u16; 4]> = KeycodeBuffer::new();
+        for k in 0..3 {
+            buf.put(k).unwrap();
+            assert_eq!(buf.take(), Some(k));
+        }
+
+        // head is at the last slot, so tail wraps around behind it from here on.
+        buf.put(0xe0).unwrap();
+        buf.put(0x75).unwrap();
+        assert_eq!(buf.len(), 2);
+        buf.put(0xf0).unwrap();
+        assert_eq!(buf.len(), 3);
+        assert!(buf.is_full());
+
+        assert_eq!(buf.take(), Some(0xe0));
+        assert_eq!(buf.len(), 2);
+        assert_eq!(buf.take(), Some(0x75));
+        assert_eq!(buf.take(), Some(0xf0));
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[cfg(feature = "use-timer")]
+    #[test]
+    fn stamp_is_not_part_of_key() {
+        let mut buf : KeycodeBuffer<[u16; 4]> = KeycodeBuffer::new();
+        buf.set_clock(0x5a);
+        buf.put(0xf0).unwrap();
+        assert_eq!(buf.peek(), Some(0xf0));
+        assert_eq!(buf.take(), Some(0xf0));
+    }
+
+    #[cfg(feature = "use-timer")]
+    #[test]
+    fn flush_older_than_keeps_young_keys() {
+        let mut buf : KeycodeBuffer<[u16; 8]> = KeycodeBuffer::new();
+        // Across the wrap, to make sure ages are taken modulo 256.
+        buf.set_clock(0xfe);
+        buf.put(0x1c).unwrap();
+        buf.set_clock(0x02);
+        buf.put(0xf0).unwrap();
+        buf.set_clock(0x05);
+        buf.put(0x1c).unwrap();
+
+        buf.set_clock(0x06);
+        buf.flush_older_than(4);
+        assert_eq!(buf.len(), 2);
+        assert_eq!(buf.peek(), Some(0xf0));
+
+        // Exactly ticks old is young enough.
+        buf.set_clock(0x07);
+        buf.flush_older_than(5);
+        assert_eq!(buf.len(), 2);
+
+        buf.set_clock(0x40);
+        buf.flush_older_than(4);
+        assert!(buf.is_empty());
+        assert_eq!(buf.dropped(), 0);
+    }
+
+    // 0xED: start, data LSb first, even number of ones so parity 1, stop.
+    const ED_BITS : [bool; 11] =
+        [false, true, false, true, true, false, true, true, true, true, true];
+
+    #[test]
+    fn shift_out_whole_frame() {
+        let mut key_out = KeyOut::new();
+        key_out.put(0xed).unwrap();
+
+        for (i, b) in ED_BITS.iter().enumerate() {
+            assert_eq!(key_out.is_in_stop_bit(), i == 10);
+            assert_eq!(key_out.shift_out(), Some(*b));
+        }
+    }
+
+    #[test]
+    fn shift_out_after_last_data_and_stop_bits() {
+        let mut key_out = KeyOut::new();
+        key_out.put(0xed).unwrap();
+        for _ in 0..8 {
+            let _ = key_out.shift_out();
+        }
+
+        assert_eq!(key_out.shift_out(), Some(true)); // Last data bit.
+        assert_eq!(key_out.shift_out(), Some(true)); // Parity.
+        assert!(!key_out.take_ack_clock());
+        assert_eq!(key_out.shift_out(), Some(true)); // Stop.
+        assert!(key_out.is_empty());
+
+        // Underrun: no stray bit, and the ACK clock is still expected exactly once.
+        assert_eq!(key_out.shift_out(), None);
+        assert!(key_out.take_ack_clock());
+        assert!(!key_out.take_ack_clock());
+        assert_eq!(key_out.shift_out(), None);
+    }
+
+    #[test]
+    fn xt_frame_bits_in_order() {
+        assert_eq!(xt_frame(0x1E), 0b00_0111_1010);
+
+        // 0x1E (A) goes out as 0 1, then 0 1 1 1 1 0 0 0.
+        let mut bits = [0xff; 12];
+        let mut n : usize = 0;
+        for bit in XtFrameBits::new(0x1E) {
+            bits[n] = bit;
+            n = n + 1;
+        }
+        assert_eq!(n, XT_FRAME_BITS as usize);
+        assert_eq!(&bits[..n], &[0, 1, 0, 1, 1, 1, 1, 0, 0, 0]);
+
+        // Start bits and nothing else.
+        assert!(XtFrameBits::new(0x00).eq([0, 1, 0, 0, 0, 0, 0, 0, 0, 0].iter().cloned()));
+        assert!(XtFrameBits::new(0xff).eq([0, 1, 1, 1, 1, 1, 1, 1, 1, 1].iter().cloned()));
+    }
+
+    #[test]
+    fn put_refused_until_acked() {
+        let mut key_out = KeyOut::new();
+        key_out.put(0xed).unwrap();
+        assert!(key_out.put(0x02).is_err());
+
+        for b in ED_BITS.iter() {
+            assert_eq!(key_out.shift_out(), Some(*b));
+        }
+        // Out, but not ACKed yet.
+        assert!(key_out.put(0x02).is_err());
+
+        // As the interrupt does on the ACK clock.
+        assert!(key_out.take_ack_clock());
+        key_out.clear();
+
+        // 0x02: one data bit set, so parity 0.
+        key_out.put(0x02).unwrap();
+        let bits = [false, false, true, false, false, false, false, false, false, false, true];
+        for b in bits.iter() {
+            assert_eq!(key_out.shift_out(), Some(*b));
+        }
+        assert!(key_out.is_empty());
+        assert_eq!(key_out.shift_out(), None);
+    }
+
+    #[test]
+    fn clear_drops_frame() {
+        let mut key_out = KeyOut::new();
+        key_out.put(0xed).unwrap();
+        let _ = key_out.shift_out();
+        key_out.clear();
+
+        assert!(key_out.is_empty());
+        assert!(!key_out.take_ack_clock());
+        assert_eq!(key_out.shift_out(), None);
+        assert!(key_out.put(0x02).is_ok());
+    }
+
+    #[test]
+    fn sequence_fits_pause() {
+        let pause = [0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5];
+        let seq = DefaultSequence::from_slice(&pause).unwrap();
+        assert_eq!(seq.as_slice(), &pause[..]);
+
+        assert!(DefaultSequence::from_slice(&[0; 7]).is_err());
+        assert_eq!(DefaultSequence::from_slice(&[]).unwrap().as_slice(), &[]);
     }
 }
@@ -1,32 +1,54 @@
-mod keymap {
-    static KEYCODE_LUT : [u8; 132] =
-    // 0    1    2    3    4    5    6    7    8    9    A    B    C    D    E    F
-    [0x00,0x43,0x00,0x3F,0x3D,0x3B,0x3C,0x58,0x00,0x44,0x42,0x40,0x3E,0x0F,0x29,0x00,
-    0x00,0x38,0x2A,0x00,0x1D,0x10,0x02,0x00,0x00,0x00,0x2C,0x1F,0x1E,0x11,0x03,0x00,
-    0x00,0x2E,0x2D,0x20,0x12,0x05,0x04,0x00,0x00,0x39,0x2F,0x21,0x14,0x13,0x06,0x00,
-    0x00,0x31,0x30,0x23,0x22,0x15,0x07,0x00,0x00,0x00,0x32,0x24,0x16,0x08,0x09,0x00,
-    0x00,0x33,0x25,0x17,0x18,0x0B,0x0A,0x00,0x00,0x34,0x35,0x26,0x27,0x19,0x0C,0x00,
-    0x00,0x00,0x28,0x00,0x1A,0x0D,0x00,0x00,0x3A,0x36,0x1C,0x1B,0x00,0x2B,0x00,0x00,
-    0x00,0x00,0x00,0x00,0x00,0x00,0x0E,0x00,0x00,0x4F,0x00,0x4B,0x47,0x00,0x00,0x00,
-    0x52,0x53,0x50,0x4C,0x4D,0x48,0x01,0x45,0x57,0x4E,0x51,0x4A,0x37,0x49,0x46,0x00,
-    0x00,0x00,0x00,0x41];
-
-    pub fn to_xt(at_in : u8) -> u8 {
-        let ptr = &KEYCODE_LUT[0] as * const u8;
-        if at_in < 132 {
-            unsafe { *ptr.offset(at_in as isize) }
-        } else {
-            0
-        }
-    }
-}
+use scancode;
+use leds::{LED_SCROLL, LED_NUM, LED_CAPS};
 
-#[derive(Debug)]
+// Pause is the one key that has no break code and is sent as one long sequence.
+const PAUSE_AT : [u8; 8] = [0xe1, 0x14, 0x77, 0xe1, 0xf0, 0x14, 0xf0, 0x77];
+#[cfg(not(feature = "xt-strict"))]
+static PAUSE_XT : [u8; 6] = [0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5];
+// No E1 on an XT either; there, Pause is Ctrl+Num Lock.
+#[cfg(feature = "xt-strict")]
+static PAUSE_XT : [u8; 4] = [0x1d, 0x45, 0xc5, 0x9d];
+// Corrupt frames in a row before we give up on resends and reset the keyboard. A good
+// frame in between starts the count over.
+const BAD_FRAMES_BEFORE_RESET : u8 = 3;
+// Pressing Scroll Lock while Num Lock is held types the keyboard ID to the PC instead.
+const IDENTIFY_CHORD : u8 = LED_SCROLL | LED_NUM;
+// Pressing Caps Lock while Num Lock is held switches the keyboard between sets 2 and 3.
+const SCAN_SET_CHORD : u8 = LED_CAPS | LED_NUM;
+// Pressing Scroll Lock while Caps Lock is held blinks out the trace on the LEDs.
+#[cfg(feature = "trace")]
+const TRACE_CHORD : u8 = LED_SCROLL | LED_CAPS;
+// Keys that drive an LED: (set 1 make code, LED bit), first match wins. Looked up after
+// translation and scancode::REMAP, so a key remapped onto Caps Lock lights the Caps LED.
+// An LED bit of 0 forwards the key without touching the LEDs, e.g. (0x3a, 0) for a
+// keyboard with no Caps Lock LED.
+const LOCK_KEYS : &'static [(u8, u8)] = &[
+    (0x46, LED_SCROLL),
+    (0x45, LED_NUM),
+    (0x3a, LED_CAPS),
+];
+// Non-modifier keys remembered as held, most recent first. Modifiers are always tracked.
+const RECENT_KEYS : usize = 2;
+
+#[derive(Debug, PartialEq)]
 pub enum Cmd {
     WaitForKey,
     ClearBuffer, // If Reset Occurs.
-    ToggleLed(u8),
+    ToggleLed(u8), // Bits to flip; the new mask comes back in LedToggled.
     SendXTKey(u8),
+    SendXTSequence(&'static [u8]),
+    Reset, // Resync with the keyboard after losing track of the AT stream.
+    Identify, // Type the keyboard ID to the PC as hex digits.
+    HostReset, // Reset the keyboard and send the PC its BAT code.
+    SetScanSet(u8), // Put the keyboard in this set; the set it ends up in comes back.
+    #[cfg(feature = "trace")]
+    DumpTrace, // Blink the trace out on the LEDs.
+    #[cfg(feature = "wiring-test")]
+    XtTestPattern, // Send the PC a fixed make/break pair, then pause.
+    #[cfg(feature = "passthrough-unknown")]
+    SendUnknownKey(u8), // Like SendXTKey, but untranslated; logged as well.
+    #[cfg(feature = "use-timer")]
+    FlushStale, // Drop buffered keys that have waited too long to still mean anything.
 }
 
 pub enum ProcReply {
@@ -34,70 +56,357 @@ pub enum ProcReply {
     NothingToDo,
     GrabbedKey(u8),
     SentKey(u8),
+    SentSequence,
     ClearedBuffer,
     LedToggled(u8),
     KeyboardReset,
+    BadFrame, // The keyboard was asked to resend a corrupt frame.
+    KeyboardError, // Keyboard sent an overrun/error code instead of a key.
+    DidReset,
+    Identified,
+    AtAck(u8), // A queued command (the first byte) went out and was ACKed.
+    AtNak(u8), // A queued command was refused or never answered.
+    HostResetRequested, // The PC held the XT clock low; seen only in between frames.
+    ScanSetSelected(u8), // The set the keyboard is in now, which is the old one on failure.
+    #[cfg(feature = "trace")]
+    TraceDumped,
+    #[cfg(feature = "wiring-test")]
+    SentTestPattern,
+    #[cfg(feature = "use-timer")]
+    FlushedStale,
     //SentEcho,
 }
 
+// Nonzero, so an empty trace slot can be told apart from a recorded one.
+#[cfg(feature = "trace")]
+impl Cmd {
+    pub fn tag(&self) -> u8 {
+        match self {
+            &Cmd::WaitForKey => { 1 },
+            &Cmd::ClearBuffer => { 2 },
+            &Cmd::ToggleLed(_) => { 3 },
+            &Cmd::SendXTKey(_) => { 4 },
+            &Cmd::SendXTSequence(_) => { 5 },
+            &Cmd::Reset => { 6 },
+            &Cmd::Identify => { 7 },
+            &Cmd::HostReset => { 9 },
+            &Cmd::SetScanSet(_) => { 10 },
+            #[cfg(feature = "wiring-test")]
+            &Cmd::XtTestPattern => { 11 },
+            &Cmd::DumpTrace => { 12 },
+            #[cfg(feature = "passthrough-unknown")]
+            &Cmd::SendUnknownKey(_) => { 8 },
+            #[cfg(feature = "use-timer")]
+            &Cmd::FlushStale => { 13 },
+        }
+    }
+}
+
 impl ProcReply {
     pub fn init() -> ProcReply {
         ProcReply::NothingToDo
     }
+
+    #[cfg(feature = "trace")]
+    pub fn tag(&self) -> u8 {
+        match self {
+            &ProcReply::NothingToDo => { 0 },
+            &ProcReply::GrabbedKey(_) => { 1 },
+            &ProcReply::SentKey(_) => { 2 },
+            &ProcReply::SentSequence => { 3 },
+            &ProcReply::ClearedBuffer => { 4 },
+            &ProcReply::LedToggled(_) => { 5 },
+            &ProcReply::KeyboardReset => { 6 },
+            &ProcReply::BadFrame => { 7 },
+            &ProcReply::KeyboardError => { 8 },
+            &ProcReply::DidReset => { 9 },
+            &ProcReply::Identified => { 10 },
+            &ProcReply::AtAck(_) => { 11 },
+            &ProcReply::AtNak(_) => { 12 },
+            &ProcReply::HostResetRequested => { 13 },
+            &ProcReply::ScanSetSelected(_) => { 14 },
+            #[cfg(feature = "wiring-test")]
+            &ProcReply::SentTestPattern => { 15 },
+            &ProcReply::TraceDumped => { 16 },
+            #[cfg(feature = "use-timer")]
+            &ProcReply::FlushedStale => { 17 },
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum State {
     NotInKey,
     SimpleKey(u8),
     PossibleBreakCode,
     KnownBreakCode(u8),
-    UnmodifiedKey(u8),
+    #[cfg_attr(feature = "xt-strict", allow(dead_code))]
+    ExtendedPrefix,
+    ExtendedKey,
+    PossibleExtendedBreakCode,
     ToggleLedFirst(u8),
-    // InPause(u8), // Number of keycodes in pause left to handle- alternate impl.
+    InPause(u8), // Number of keycodes in pause matched so far.
+    PauseKey,
     Inconsistent,
     ExpectingBufferClear,
+    Resetting,
+    Identifying,
+    ResettingForHost,
+    SwitchingScanSet(u8),
+    #[cfg(feature = "trace")]
+    DumpingTrace,
+    #[cfg(feature = "wiring-test")]
+    WiringTest,
+    #[cfg(feature = "passthrough-unknown")]
+    UnknownKey(u8), // Raw AT code, with bit 7 set for a break.
+    #[cfg(feature = "use-timer")]
+    FlushingStale,
+}
+
+// XT keys the PC was sent a make code for and no break code yet. If we lose track of the
+// keyboard, these would stay down on the PC forever, so they get a break code of our own
+// before starting over. Only the modifiers and the last RECENT_KEYS other keys fit; those
+// are the ones that do damage when stuck.
+#[derive(Clone, Copy)]
+pub struct HeldKeys {
+    modifiers : u8,
+    recent : [u8; RECENT_KEYS], // Zero is an empty slot.
+}
+
+const MODIFIERS : [u8; 4] = [0x1d, 0x2a, 0x36, 0x38]; // Ctrl, Left Shift, Right Shift, Alt
+// Keys some keyboards send a make code for and never a break: the ACPI Power, Sleep and
+// Wake keys, all E0-prefixed. They'd sit in recent forever, pushing out keys that can
+// really be held, and get a break of our own the PC never asked for. record() only sees
+// the byte after the E0, so these codes are left out whether they had one or not; no key
+// in the tables sends them without.
+const MAKE_ONLY_KEYS : [u8; 3] = [0x5e, 0x5f, 0x63];
+
+impl HeldKeys {
+    const fn new() -> HeldKeys {
+        HeldKeys { modifiers : 0, recent : [0; RECENT_KEYS] }
+    }
+
+    // Anything the PC was sent: make codes, break codes, and the E0 prefix (ignored).
+    fn record(&mut self, xt_code : u8) -> () {
+        let key : u8 = xt_code & 0x7f;
+        let make : bool = xt_code & 0x80 == 0;
+
+        match MODIFIERS.iter().position(|m| *m == key) {
+            Some(i) if make => { self.modifiers = self.modifiers | (1 << i); },
+            Some(i) => { self.modifiers = self.modifiers & !(1 << i); },
+            None if xt_code == 0xe0 => { },
+            None if MAKE_ONLY_KEYS.iter().any(|k| *k == key) => { },
+            None => {
+                for slot in self.recent.iter_mut() {
+                    if *slot == key {
+                        *slot = 0;
+                    }
+                }
+
+                if make {
+                    for i in (1..RECENT_KEYS).rev() {
+                        self.recent[i] = self.recent[i - 1];
+                    }
+                    self.recent[0] = key;
+                }
+            },
+        }
+    }
+
+    fn for_each_break<F>(&self, mut f : F) -> () where F: FnMut(u8) {
+        for (i, m) in MODIFIERS.iter().enumerate() {
+            if self.modifiers & (1 << i) != 0 {
+                f(*m | 0x80);
+            }
+        }
+
+        for k in self.recent.iter() {
+            if *k != 0 {
+                f(*k | 0x80);
+            }
+        }
+    }
 }
 
 pub struct Fsm {
     curr_state : State,
-    expecting_pause : bool,
-    led_mask : u8
+    locks_held : u8, // LED bits of lock keys currently held down.
+    bad_frames : u8, // Consecutive BadFrame replies.
+    scan_set : u8, // Which set the keyboard was put in; picks the translation table.
+    held : HeldKeys,
+    // AT code of the last make passed on, with 0xE000 added for E0 keys; 0 after a break.
+    #[cfg_attr(not(feature = "suppress-repeat"), allow(dead_code))]
+    last_make : u16,
+    injected : Option<Cmd>, // Waiting to be handed out by run().
+    injected_out : bool, // The last command run() handed out was an injected one.
 }
 
 impl Fsm {
     pub fn start() -> Fsm {
-        Fsm { curr_state : State::NotInKey, expecting_pause : false, led_mask : 0 }
+        Fsm {
+            curr_state : State::NotInKey,
+            locks_held : 0,
+            bad_frames : 0,
+            scan_set : 2,
+            held : HeldKeys::new(),
+            last_make : 0,
+            injected : None,
+            injected_out : false,
+        }
+    }
+
+    // Survives reset(); it describes the keyboard, not the state of the machine.
+    pub fn set_scan_set(&mut self, set : u8) -> () {
+        self.scan_set = set;
+    }
+
+    // Back to the state start() returns, forgetting any prefix, half-matched sequence, or
+    // held key. Call release_held() first if the PC should hear about the held keys.
+    pub fn reset(&mut self) -> () {
+        self.curr_state = State::NotInKey;
+        self.locks_held = 0;
+        self.bad_frames = 0;
+        self.held = HeldKeys::new();
+        self.last_make = 0;
+        self.injected_out = false;
+    }
+
+    // wiring-test: send the test pattern forever instead of translating keys. The first
+    // run() goes straight to it; nothing leads back out but reset().
+    #[cfg(feature = "wiring-test")]
+    pub fn start_wiring_test(&mut self) -> () {
+        self.curr_state = State::WiringTest;
+    }
+
+    // Hand f a break code for every key the PC still thinks is down, and forget them.
+    pub fn release_held<F>(&mut self, f : F) -> () where F: FnMut(u8) {
+        self.held.for_each_break(f);
+        self.held = HeldKeys::new();
     }
 
+    // make-only: hand f a break code for every modifier the PC still thinks is down, and
+    // forget them. Only in between keys; in the middle of one, nothing happens. Other keys
+    // are left alone, as the PC never gets their breaks anyway.
+    #[cfg(feature = "make-only")]
+    pub fn release_modifiers<F>(&mut self, f : F) -> () where F: FnMut(u8) {
+        if let State::NotInKey = self.curr_state {
+            let modifiers = HeldKeys { modifiers : self.held.modifiers, recent : [0; RECENT_KEYS] };
+            modifiers.for_each_break(f);
+            self.held.modifiers = 0;
+        }
+    }
+
+    // Have the next run() return cmd, ahead of whatever the machine would do next. The
+    // current command still completes first; the machine then moves to its next state as
+    // usual, but that state's command waits until the injected one is done. The injected
+    // command's reply is not fed to the machine, so it can't change state: don't inject
+    // WaitForKey, whose reply is a key. Only one can be pending; a second replaces it.
+    #[allow(dead_code)]
+    pub fn inject(&mut self, cmd : Cmd) -> () {
+        self.injected = Some(cmd);
+    }
+
+    // Total over every (state, reply) pair: an unexpected reply is an Err, never a panic.
+    // On Err, the caller should reset() before calling run() again.
     pub fn run(&mut self, curr_reply : &ProcReply) -> Result<Cmd, ()> {
-        let next_state = self.next_state(curr_reply);
+        let next_cmd = if self.injected_out {
+            // Reply to the injected command. Pick up where it interrupted.
+            self.injected_out = false;
+            self.cmd_for(&self.curr_state)
+        } else {
+            if let &ProcReply::GrabbedKey(_) = curr_reply {
+                self.bad_frames = 0;
+            }
+
+            self.curr_state = self.next_state(curr_reply);
+
+            match self.injected.take() {
+                Some(cmd) => { self.injected_out = true; Ok(cmd) },
+                None => { self.cmd_for(&self.curr_state) },
+            }
+        };
+
+        if let &Ok(Cmd::SendXTKey(k)) = &next_cmd {
+            self.held.record(k);
+        }
+
+        next_cmd
+    }
 
-        let next_cmd = match &next_state {
+    fn cmd_for(&self, state : &State) -> Result<Cmd, ()> {
+        match state {
             &State::NotInKey => { Ok(Cmd::WaitForKey) },
-            &State::SimpleKey(k) => { Ok(Cmd::SendXTKey(keymap::to_xt(k))) },
+            &State::SimpleKey(k) => { Ok(Cmd::SendXTKey(k)) },
             &State::PossibleBreakCode => { Ok(Cmd::WaitForKey) },
-            &State::KnownBreakCode(b) => { Ok(Cmd::SendXTKey(keymap::to_xt(b) | 0x80)) },
-            &State::UnmodifiedKey(u) => { Ok(Cmd::SendXTKey(u)) },
+            &State::KnownBreakCode(b) => { Ok(Cmd::SendXTKey(b | 0x80)) },
+            &State::ExtendedPrefix => { Ok(Cmd::SendXTKey(0xe0)) },
+            &State::ExtendedKey => { Ok(Cmd::WaitForKey) },
+            &State::PossibleExtendedBreakCode => { Ok(Cmd::WaitForKey) },
             &State::ToggleLedFirst(l) => {
-                match l {
-                    0x7e => { Ok(Cmd::ToggleLed(self.led_mask ^ 0x01)) }, // Scroll
-                    0x77 => { Ok(Cmd::ToggleLed(self.led_mask ^ 0x02)) }, // Num
-                    0x58 => { Ok(Cmd::ToggleLed(self.led_mask ^ 0x04)) }, // Caps
-                    _ => { Err(()) }
+                match self.lock_bit(l) {
+                    // Unreachable: only keys with a lock bit get here.
+                    0 => { Err(()) },
+                    b => { Ok(Cmd::ToggleLed(b)) },
                 }
             }
+            &State::InPause(_) => { Ok(Cmd::WaitForKey) },
+            &State::PauseKey => { Ok(Cmd::SendXTSequence(&PAUSE_XT)) },
             &State::ExpectingBufferClear => { Ok(Cmd::ClearBuffer) }
+            &State::Resetting => { Ok(Cmd::Reset) }
+            &State::Identifying => { Ok(Cmd::Identify) }
+            &State::ResettingForHost => { Ok(Cmd::HostReset) }
+            &State::SwitchingScanSet(set) => { Ok(Cmd::SetScanSet(set)) }
+            #[cfg(feature = "trace")]
+            &State::DumpingTrace => { Ok(Cmd::DumpTrace) }
+            #[cfg(feature = "wiring-test")]
+            &State::WiringTest => { Ok(Cmd::XtTestPattern) }
+            #[cfg(feature = "passthrough-unknown")]
+            &State::UnknownKey(k) => { Ok(Cmd::SendUnknownKey(k)) }
+            #[cfg(feature = "use-timer")]
+            &State::FlushingStale => { Ok(Cmd::FlushStale) }
             &State::Inconsistent => { Err(()) }
-        };
-
-        self.curr_state = next_state;
-        next_cmd
+        }
     }
 
     fn next_state(&mut self, curr_reply : &ProcReply) -> State {
-        match (&self.curr_state, curr_reply) {
+        // A copy, so the arms below are free to update the rest of self.
+        let curr_state : State = self.curr_state;
+
+        match (&curr_state, curr_reply) {
+            // The PC and the keyboard can both want us at once. WaitForKey only looks at
+            // the PC in between frames, so the frame before has already gone out, and
+            // the order from here is fixed: reset for the PC (KeyboardReset), then throw
+            // away what the keyboard sent meanwhile (ClearedBuffer). Anything we were in
+            // the middle of is dropped, and the PC forgets its held keys on a reset, so
+            // we do too.
+            (_, &ProcReply::HostResetRequested) => {
+                self.held = HeldKeys::new();
+                State::ResettingForHost
+            },
             (_, &ProcReply::KeyboardReset) => { State::ExpectingBufferClear },
+            // The keyboard lost keys. Whatever we were in the middle of is now garbage, and
+            // so is anything still queued behind the error code.
+            (_, &ProcReply::KeyboardError) => { State::ExpectingBufferClear },
+            // Only WaitForKey reports a bad frame, so whatever we were waiting in, keep
+            // waiting there for the resent frame. If resends aren't helping, we are most
+            // likely out of step with the keyboard's framing.
+            (&s, &ProcReply::BadFrame) => {
+                self.bad_frames = self.bad_frames + 1;
+                if self.bad_frames >= BAD_FRAMES_BEFORE_RESET {
+                    State::Resetting
+                } else {
+                    s
+                }
+            },
+            // Queued commands also only go out from WaitForKey, in between keys, so the
+            // same goes for their outcome. Nothing in the FSM waits on one yet.
+            (&s, &ProcReply::AtAck(_)) => { s },
+            (&s, &ProcReply::AtNak(_)) => { s },
+            // Anything half-received is gone, and the keyboard comes back with every key up.
+            (&State::Resetting, &ProcReply::DidReset) => {
+                self.reset();
+                after_reset()
+            },
             (&State::NotInKey, &ProcReply::NothingToDo) => { State::NotInKey },
             (&State::NotInKey, &ProcReply::GrabbedKey(k)) => {
                 match k {
@@ -108,39 +417,814 @@ impl Fsm {
                     0xee => { State::NotInKey },
 
                     0xf0 => { State::PossibleBreakCode },
-                    0xe0 => { State::UnmodifiedKey(k) },
-                    0xe1 => {
-                        self.expecting_pause = true;
-                        State::UnmodifiedKey(k)
+                    0xe0 => { extended_prefix() },
+                    0xe1 => { State::InPause(1) },
+                    // Set 3 has a plain make/break code for Pause. The XT sequence goes out
+                    // on the make; the break translates to nothing.
+                    0x62 if self.scan_set == 3 => { State::PauseKey },
+
+                    // Lock keys flip their LED on the make code. Typematic repeats
+                    // arrive as more make codes, so only the first one while the key
+                    // is held counts.
+                    _ if self.lock_bit(k) != 0 => {
+                        let bit = self.lock_bit(k);
+                        if self.locks_held & bit == 0 {
+                            self.locks_held = self.locks_held | bit;
+                            // The chord's last key is swallowed: no LED, no make code.
+                            // Its break still goes to the PC, which ignores it.
+                            if bit == LED_SCROLL && self.locks_held & IDENTIFY_CHORD == IDENTIFY_CHORD {
+                                State::Identifying
+                            } else if bit == LED_CAPS && self.locks_held & SCAN_SET_CHORD == SCAN_SET_CHORD {
+                                State::SwitchingScanSet(if self.scan_set == 3 { 2 } else { 3 })
+                            } else if let Some(next) = self.trace_chord(bit) {
+                                next
+                            } else {
+                                State::ToggleLedFirst(k)
+                            }
+                        } else {
+                            let next = make_code(self.scan_set, k);
+                            self.filter_repeat(k as u16, next)
+                        }
                     },
 
-                    _ => { State::SimpleKey(k) }
+                    _ => {
+                        let next = make_code(self.scan_set, k);
+                        self.filter_repeat(k as u16, next)
+                    }
                 }
             },
             (&State::SimpleKey(_), &ProcReply::SentKey(_)) => { State::NotInKey },
             (&State::PossibleBreakCode, &ProcReply::GrabbedKey(k)) => {
+                if k != 0xf0 {
+                    self.last_make = 0;
+                }
+
                 match k {
-                    // LEDs => State::ToggleLed()
-                    0x7e => { State::ToggleLedFirst(k) },
-                    0x77 => { if self.expecting_pause {
-                                self.expecting_pause = false;
-                                State::KnownBreakCode(k)
-                            } else {
-                                State::ToggleLedFirst(k)
-                            }
+                    // A repeated prefix (line noise, or a keyboard resend) is still just
+                    // the one prefix.
+                    0xf0 => { State::PossibleBreakCode },
+                    // LEDs were already handled on the make code.
+                    _ if self.lock_bit(k) != 0 => {
+                        self.locks_held = self.locks_held & !self.lock_bit(k);
+                        break_code(self.scan_set, k)
                     },
-                    0x58 => { State::ToggleLedFirst(k) },
-                    _ => { State::KnownBreakCode(k) }
+                    _ => { break_code(self.scan_set, k) }
                 }
             },
             (&State::KnownBreakCode(_), &ProcReply::SentKey(_)) => { State::NotInKey },
-            (&State::UnmodifiedKey(_), &ProcReply::SentKey(_)) => { State::NotInKey },
-            (&State::ToggleLedFirst(l), &ProcReply::LedToggled(m)) => {
-                self.led_mask = m;
-                State::KnownBreakCode(l)
+            // XT also uses E0 as an extended prefix, so it goes to the PC first. The key
+            // that follows is translated using its own table.
+            (&State::ExtendedPrefix, &ProcReply::SentKey(_)) => { State::ExtendedKey },
+            (&State::ExtendedKey, &ProcReply::GrabbedKey(k)) => {
+                match k {
+                    0xe0 => { State::ExtendedKey },
+                    0xf0 => { State::PossibleExtendedBreakCode },
+                    _ => {
+                        let next = extended_make_code(k);
+                        self.filter_repeat(0xe000 | k as u16, next)
+                    }
+                }
+            },
+            (&State::PossibleExtendedBreakCode, &ProcReply::GrabbedKey(k)) => {
+                if k != 0xf0 {
+                    self.last_make = 0;
+                }
+
+                match k {
+                    0xf0 => { State::PossibleExtendedBreakCode },
+                    _ => { extended_break_code(k) }
+                }
+            },
+            // Nothing goes to the PC until the whole sequence has arrived. Anything
+            // unexpected mid-sequence means we lost sync; drop what we have.
+            (&State::InPause(n), &ProcReply::GrabbedKey(k)) => {
+                if k != PAUSE_AT[n as usize] {
+                    State::NotInKey
+                } else if (n as usize) + 1 == PAUSE_AT.len() {
+                    State::PauseKey
+                } else {
+                    State::InPause(n + 1)
+                }
+            },
+            (&State::PauseKey, &ProcReply::SentSequence) => { State::NotInKey },
+            (&State::ToggleLedFirst(l), &ProcReply::LedToggled(_)) => {
+                let next = make_code(self.scan_set, l);
+                self.filter_repeat(l as u16, next)
             },
             (&State::ExpectingBufferClear, &ProcReply::ClearedBuffer) => { State::NotInKey },
+            (&State::Identifying, &ProcReply::Identified) => { State::NotInKey },
+            #[cfg(feature = "trace")]
+            (&State::DumpingTrace, &ProcReply::TraceDumped) => { State::NotInKey },
+            #[cfg(feature = "use-timer")]
+            (&State::FlushingStale, &ProcReply::FlushedStale) => { State::NotInKey },
+            // Anything typed while the keyboard was switching may be in either set, so
+            // throw it away. Keys still held send their breaks in the new set, which is
+            // what the new table expects.
+            (&State::SwitchingScanSet(_), &ProcReply::ScanSetSelected(set)) => {
+                self.scan_set = set;
+                State::ExpectingBufferClear
+            },
+            #[cfg(feature = "passthrough-unknown")]
+            (&State::UnknownKey(_), &ProcReply::SentKey(_)) => { State::NotInKey },
+            #[cfg(feature = "wiring-test")]
+            (&State::WiringTest, &ProcReply::NothingToDo) => { State::WiringTest },
+            #[cfg(feature = "wiring-test")]
+            (&State::WiringTest, &ProcReply::SentTestPattern) => { State::WiringTest },
+            // Every command gets exactly one kind of reply, so each state above only ever
+            // sees the replies to its own command, plus (for the WaitForKey states) the
+            // interruptions handled at the top. Nothing is left for this arm but a reply
+            // that doesn't belong to the command just run, which only a mistake in the
+            // idle loop can produce:
+            //   SimpleKey, KnownBreakCode, ExtendedPrefix, UnknownKey: anything but SentKey
+            //   ToggleLedFirst: anything but LedToggled
+            //   PauseKey: anything but SentSequence
+            //   ExpectingBufferClear: anything but ClearedBuffer
+            //   Resetting: anything but DidReset
+            //   Identifying: anything but Identified
+            //   DumpingTrace: anything but TraceDumped
+            //   FlushingStale: anything but FlushedStale
+            //   SwitchingScanSet: anything but ScanSetSelected
+            //   ResettingForHost: anything but KeyboardReset (caught above)
+            //   Any state but NotInKey: NothingToDo, which is only ever the first reply
+            //   Inconsistent: anything; run() returns Err and the caller starts over
+            // Rather than trust that, it lands here, and run() returns Err for the caller
+            // to recover from. Nothing panics.
             (_, _) => { State::Inconsistent },
         }
     }
+
+    // The state for the lock key bit just pressed, if it completes TRACE_CHORD.
+    #[cfg(feature = "trace")]
+    fn trace_chord(&self, bit : u8) -> Option<State> {
+        if bit == LED_SCROLL && self.locks_held & TRACE_CHORD == TRACE_CHORD {
+            Some(State::DumpingTrace)
+        } else {
+            None
+        }
+    }
+
+    #[cfg(not(feature = "trace"))]
+    fn trace_chord(&self, _bit : u8) -> Option<State> {
+        None
+    }
+
+    // Pass on the state for a make code, unless suppress-repeat is on and it's the same
+    // key as the last make with no break in between: a typematic repeat.
+    #[cfg(not(feature = "suppress-repeat"))]
+    fn filter_repeat(&mut self, _key : u16, next : State) -> State {
+        next
+    }
+
+    // suppress-repeat: the PC only sees the first make of a held key, whatever the
+    // keyboard's typematic settings. As with make-only, an extended key's E0 has already
+    // gone out by the time its repeat is recognized; XT BIOSes ignore a lone E0.
+    #[cfg(feature = "suppress-repeat")]
+    fn filter_repeat(&mut self, key : u16, next : State) -> State {
+        if key == self.last_make {
+            State::NotInKey
+        } else {
+            self.last_make = key;
+            next
+        }
+    }
+
+    // Lock keys are recognized by what they translate to, so this works the same whichever
+    // scan set the keyboard is in.
+    fn lock_bit(&self, at_code : u8) -> u8 {
+        match scancode::translate(self.scan_set, at_code) {
+            Some(k) => {
+                match LOCK_KEYS.iter().find(|&&(code, _)| code == k) {
+                    Some(&(_, bit)) => { bit },
+                    None => { 0 },
+                }
+            },
+            None => { 0 },
+        }
+    }
+}
+
+// SimpleKey and KnownBreakCode hold already-translated XT codes. AT keys without an XT
+// equivalent are silently dropped (see unknown_key).
+fn make_code(set : u8, at_code : u8) -> State {
+    match scancode::translate(set, at_code) {
+        Some(k) => { State::SimpleKey(k) },
+        None => { unknown_key(at_code) },
+    }
+}
+
+fn break_code(set : u8, at_code : u8) -> State {
+    match scancode::translate(set, at_code) {
+        Some(k) if forward_break(k) => { State::KnownBreakCode(k) },
+        Some(_) => { State::NotInKey },
+        None => { unknown_key(at_code | 0x80) },
+    }
+}
+
+#[cfg(not(feature = "xt-strict"))]
+fn extended_prefix() -> State {
+    State::ExtendedPrefix
+}
+
+// xt-strict: an 83-key XT never sent E0, and older BIOSes choke on it. The key after it
+// is translated on its own (see scancode::E0_FALLBACK).
+#[cfg(feature = "xt-strict")]
+fn extended_prefix() -> State {
+    State::ExtendedKey
+}
+
+fn extended_make_code(at_code : u8) -> State {
+    match scancode::translate_e0(at_code) {
+        Some(k) => { State::SimpleKey(k) },
+        None => { unknown_key(at_code) },
+    }
+}
+
+fn extended_break_code(at_code : u8) -> State {
+    match scancode::translate_e0(at_code) {
+        Some(k) if forward_break(k) => { State::KnownBreakCode(k) },
+        Some(_) => { State::NotInKey },
+        None => { unknown_key(at_code | 0x80) },
+    }
+}
+
+#[cfg(not(feature = "use-timer"))]
+fn after_reset() -> State {
+    State::NotInKey
+}
+
+// use-timer: keys typed while the reset ran were typed at a keyboard that wasn't taking
+// them yet as far as the user could tell. Those that have waited long enough are dropped
+// before going back to translating; see STALE_KEY_TICKS in main.rs.
+#[cfg(feature = "use-timer")]
+fn after_reset() -> State {
+    State::FlushingStale
+}
+
+#[cfg(not(feature = "passthrough-unknown"))]
+fn unknown_key(_code : u8) -> State {
+    State::NotInKey
+}
+
+// passthrough-unknown: for working out tables for a new keyboard. The PC gets the AT code
+// as is (bit 7 set for a break, so codes above 0x7F come out ambiguous), and the debug
+// UART gets a line saying so.
+#[cfg(feature = "passthrough-unknown")]
+fn unknown_key(code : u8) -> State {
+    State::UnknownKey(code)
+}
+
+#[cfg(not(feature = "make-only"))]
+fn forward_break(_xt_code : u8) -> bool {
+    true
+}
+
+// make-only: the PC only ever sees key presses, which some DOS games handle better.
+// Modifiers would stay down on the PC for good, so the idle loop lets them go with
+// release_modifiers() once the keyboard has been quiet for a while. Extended keys still
+// send their E0 ahead of a break that no longer follows it, which XT BIOSes ignore.
+#[cfg(feature = "make-only")]
+fn forward_break(_xt_code : u8) -> bool {
+    false
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The replies the idle loop would give, one run() each; returns every command run()
+    // handed back, first one included.
+    fn drive(fsm : &mut Fsm, replies : &[ProcReply]) -> [Result<Cmd, ()>; 8] {
+        let mut cmds = [Err(()), Err(()), Err(()), Err(()), Err(()), Err(()), Err(()), Err(())];
+        for (i, reply) in replies.iter().enumerate() {
+            cmds[i] = fsm.run(reply);
+        }
+        cmds
+    }
+
+    // make-only drops the break.
+    #[cfg(not(feature = "make-only"))]
+    #[test]
+    fn key_press_and_release() {
+        let mut fsm = Fsm::start();
+        let cmds = drive(&mut fsm, &[
+            ProcReply::init(),
+            ProcReply::GrabbedKey(0x1c), // A
+            ProcReply::SentKey(0x1e),
+            ProcReply::GrabbedKey(0xf0),
+            ProcReply::GrabbedKey(0x1c),
+            ProcReply::SentKey(0x9e),
+        ]);
+
+        assert_eq!(cmds[0], Ok(Cmd::WaitForKey));
+        assert_eq!(cmds[1], Ok(Cmd::SendXTKey(0x1e)));
+        assert_eq!(cmds[2], Ok(Cmd::WaitForKey));
+        assert_eq!(cmds[3], Ok(Cmd::WaitForKey));
+        assert_eq!(cmds[4], Ok(Cmd::SendXTKey(0x9e)));
+        assert_eq!(cmds[5], Ok(Cmd::WaitForKey));
+    }
+
+    // xt-strict never sends the E0, and make-only drops the break.
+    #[cfg(not(any(feature = "xt-strict", feature = "make-only")))]
+    #[test]
+    fn extended_key_press_and_release() {
+        let mut fsm = Fsm::start();
+        let cmds = drive(&mut fsm, &[
+            ProcReply::init(),
+            ProcReply::GrabbedKey(0xe0), // Up
+            ProcReply::SentKey(0xe0),
+            ProcReply::GrabbedKey(0x75),
+            ProcReply::SentKey(0x48),
+            ProcReply::GrabbedKey(0xe0),
+            ProcReply::SentKey(0xe0),
+        ]);
+
+        assert_eq!(cmds[1], Ok(Cmd::SendXTKey(0xe0)));
+        assert_eq!(cmds[2], Ok(Cmd::WaitForKey));
+        assert_eq!(cmds[3], Ok(Cmd::SendXTKey(0x48)));
+        assert_eq!(cmds[4], Ok(Cmd::WaitForKey));
+        assert_eq!(cmds[5], Ok(Cmd::SendXTKey(0xe0)));
+
+        assert_eq!(fsm.run(&ProcReply::GrabbedKey(0xf0)), Ok(Cmd::WaitForKey));
+        assert_eq!(fsm.run(&ProcReply::GrabbedKey(0x75)), Ok(Cmd::SendXTKey(0xc8)));
+        assert_eq!(fsm.run(&ProcReply::SentKey(0xc8)), Ok(Cmd::WaitForKey));
+    }
+
+    // make-only drops the break; suppress-repeat drops the repeat (see below).
+    #[cfg(not(any(feature = "make-only", feature = "suppress-repeat")))]
+    #[test]
+    fn lock_key_toggles_led_once_per_press() {
+        let mut fsm = Fsm::start();
+        let cmds = drive(&mut fsm, &[
+            ProcReply::init(),
+            ProcReply::GrabbedKey(0x58), // Caps Lock
+            ProcReply::LedToggled(LED_CAPS),
+            ProcReply::SentKey(0x3a),
+            ProcReply::GrabbedKey(0x58), // Typematic repeat: no second toggle.
+            ProcReply::SentKey(0x3a),
+            ProcReply::GrabbedKey(0xf0),
+            ProcReply::GrabbedKey(0x58),
+        ]);
+
+        assert_eq!(cmds[1], Ok(Cmd::ToggleLed(LED_CAPS)));
+        assert_eq!(cmds[2], Ok(Cmd::SendXTKey(0x3a)));
+        assert_eq!(cmds[3], Ok(Cmd::WaitForKey));
+        assert_eq!(cmds[4], Ok(Cmd::SendXTKey(0x3a)));
+        assert_eq!(cmds[7], Ok(Cmd::SendXTKey(0xba)));
+
+        assert_eq!(fsm.run(&ProcReply::SentKey(0xba)), Ok(Cmd::WaitForKey));
+        assert_eq!(fsm.run(&ProcReply::GrabbedKey(0x58)), Ok(Cmd::ToggleLed(LED_CAPS)));
+    }
+
+    // suppress-repeat: the repeat doesn't reach the PC either, so there is still exactly
+    // one ToggleLed per press.
+    #[cfg(all(feature = "suppress-repeat", not(feature = "make-only")))]
+    #[test]
+    fn lock_key_toggles_led_once_per_press() {
+        let mut fsm = Fsm::start();
+        let cmds = drive(&mut fsm, &[
+            ProcReply::init(),
+            ProcReply::GrabbedKey(0x58), // Caps Lock
+            ProcReply::LedToggled(LED_CAPS),
+            ProcReply::SentKey(0x3a),
+            ProcReply::GrabbedKey(0x58), // Typematic repeat: swallowed whole.
+            ProcReply::GrabbedKey(0xf0),
+            ProcReply::GrabbedKey(0x58),
+        ]);
+
+        assert_eq!(cmds[1], Ok(Cmd::ToggleLed(LED_CAPS)));
+        assert_eq!(cmds[2], Ok(Cmd::SendXTKey(0x3a)));
+        assert_eq!(cmds[3], Ok(Cmd::WaitForKey));
+        assert_eq!(cmds[4], Ok(Cmd::WaitForKey));
+        assert_eq!(cmds[6], Ok(Cmd::SendXTKey(0xba)));
+
+        assert_eq!(fsm.run(&ProcReply::SentKey(0xba)), Ok(Cmd::WaitForKey));
+        assert_eq!(fsm.run(&ProcReply::GrabbedKey(0x58)), Ok(Cmd::ToggleLed(LED_CAPS)));
+    }
+
+    // Num Lock sets bit 1 of the 0xED mask and nothing else, whichever way the other two
+    // LEDs are wired.
+    #[test]
+    fn num_lock_toggles_only_num_led() {
+        let mut fsm = Fsm::start();
+        let cmds = drive(&mut fsm, &[
+            ProcReply::init(),
+            ProcReply::GrabbedKey(0x77), // Num Lock
+        ]);
+
+        assert_eq!(cmds[1], Ok(Cmd::ToggleLed(0x02)));
+        assert_eq!(LED_NUM, 0x02);
+        assert_eq!(LED_NUM & (LED_SCROLL | LED_CAPS), 0);
+        assert_eq!(fsm.run(&ProcReply::LedToggled(LED_NUM)), Ok(Cmd::SendXTKey(0x45)));
+    }
+
+    // Every LOCK_KEYS entry is reached from the key that translates to it, in both scan
+    // sets, and nothing else drives an LED.
+    #[test]
+    fn lock_keys_by_translated_code() {
+        let mut fsm = Fsm::start();
+        for &set in [2, 3].iter() {
+            fsm.set_scan_set(set);
+            let mut found = [0u8; 3];
+            for at_code in 0..0x100u16 {
+                let at_code = at_code as u8;
+                let bit = fsm.lock_bit(at_code);
+                match LOCK_KEYS.iter().position(|&(_, b)| b == bit && b != 0) {
+                    Some(i) => {
+                        assert_eq!(scancode::translate(set, at_code), Some(LOCK_KEYS[i].0));
+                        found[i] = found[i] + 1;
+                    },
+                    None => { assert_eq!(bit, 0, "set {} code {:#x}", set, at_code); },
+                }
+            }
+            assert_eq!(found, [1, 1, 1], "set {}", set);
+        }
+
+        fsm.set_scan_set(2);
+        assert_eq!(fsm.lock_bit(0x7e), LED_SCROLL);
+        assert_eq!(fsm.lock_bit(0x77), LED_NUM);
+        assert_eq!(fsm.lock_bit(0x58), LED_CAPS);
+        assert_eq!(fsm.lock_bit(0x1c), 0); // A
+    }
+
+    // Each reply, and each command, has a trace tag of its own, and the reply's fits in
+    // the bits trace::Trace keeps for it.
+    #[cfg(feature = "trace")]
+    #[test]
+    fn trace_tags_distinct() {
+        let replies = [
+            ProcReply::NothingToDo,
+            ProcReply::GrabbedKey(0x1c),
+            ProcReply::SentKey(0x1e),
+            ProcReply::SentSequence,
+            ProcReply::ClearedBuffer,
+            ProcReply::LedToggled(LED_CAPS),
+            ProcReply::KeyboardReset,
+            ProcReply::BadFrame,
+            ProcReply::KeyboardError,
+            ProcReply::DidReset,
+            ProcReply::Identified,
+            ProcReply::AtAck(0xed),
+            ProcReply::AtNak(0xed),
+            ProcReply::HostResetRequested,
+            ProcReply::ScanSetSelected(2),
+            ProcReply::TraceDumped,
+            #[cfg(feature = "wiring-test")]
+            ProcReply::SentTestPattern,
+            #[cfg(feature = "use-timer")]
+            ProcReply::FlushedStale,
+        ];
+        for (i, a) in replies.iter().enumerate() {
+            assert!(a.tag() < 32);
+            for b in replies[i + 1..].iter() {
+                assert!(a.tag() != b.tag(), "reply tag {} used twice", a.tag());
+            }
+        }
+
+        let cmds = [
+            Cmd::WaitForKey,
+            Cmd::ClearBuffer,
+            Cmd::ToggleLed(LED_CAPS),
+            Cmd::SendXTKey(0x1e),
+            Cmd::SendXTSequence(&PAUSE_XT),
+            Cmd::Reset,
+            Cmd::Identify,
+            Cmd::HostReset,
+            Cmd::SetScanSet(3),
+            Cmd::DumpTrace,
+            #[cfg(feature = "wiring-test")]
+            Cmd::XtTestPattern,
+            #[cfg(feature = "passthrough-unknown")]
+            Cmd::SendUnknownKey(0x84),
+            #[cfg(feature = "use-timer")]
+            Cmd::FlushStale,
+        ];
+        for (i, a) in cmds.iter().enumerate() {
+            assert!(a.tag() != 0 && a.tag() < 16);
+            for b in cmds[i + 1..].iter() {
+                assert!(a.tag() != b.tag(), "command tag {} used twice", a.tag());
+            }
+        }
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn trace_chord_dumps_trace() {
+        let mut fsm = Fsm::start();
+        let cmds = drive(&mut fsm, &[
+            ProcReply::init(),
+            ProcReply::GrabbedKey(0x58), // Caps Lock, held
+            ProcReply::LedToggled(LED_CAPS),
+            ProcReply::SentKey(0x3a),
+            ProcReply::GrabbedKey(0x7e), // Scroll Lock
+            ProcReply::TraceDumped,
+        ]);
+
+        assert_eq!(cmds[4], Ok(Cmd::DumpTrace));
+        assert_eq!(cmds[5], Ok(Cmd::WaitForKey));
+    }
+
+    // Resends that don't help end in a reset, after which use-timer builds drop the keys
+    // that piled up during it before taking any more.
+    #[test]
+    fn bad_frames_reset_keyboard() {
+        let mut fsm = Fsm::start();
+        let cmds = drive(&mut fsm, &[
+            ProcReply::init(),
+            ProcReply::BadFrame,
+            ProcReply::BadFrame,
+            ProcReply::BadFrame,
+        ]);
+        assert_eq!(cmds[2], Ok(Cmd::WaitForKey));
+        assert_eq!(cmds[3], Ok(Cmd::Reset));
+
+        #[cfg(feature = "use-timer")]
+        {
+            assert_eq!(fsm.run(&ProcReply::DidReset), Ok(Cmd::FlushStale));
+            assert_eq!(fsm.run(&ProcReply::FlushedStale), Ok(Cmd::WaitForKey));
+        }
+        #[cfg(not(feature = "use-timer"))]
+        assert_eq!(fsm.run(&ProcReply::DidReset), Ok(Cmd::WaitForKey));
+    }
+
+    // What 1F on its own, which has no XT equivalent, turns into.
+    #[cfg(not(feature = "passthrough-unknown"))]
+    const LONE_1F : Cmd = Cmd::WaitForKey;
+    #[cfg(feature = "passthrough-unknown")]
+    const LONE_1F : Cmd = Cmd::SendUnknownKey(0x1f);
+
+    // E0 1F is the left Windows key; 1F on its own is nothing, and gets dropped (or passed
+    // through untranslated).
+    #[cfg(not(feature = "xt-strict"))]
+    #[test]
+    fn reset_forgets_extended_prefix() {
+        let mut fsm = Fsm::start();
+        assert_eq!(fsm.run(&ProcReply::init()), Ok(Cmd::WaitForKey));
+        assert_eq!(fsm.run(&ProcReply::GrabbedKey(0xe0)), Ok(Cmd::SendXTKey(0xe0)));
+        assert_eq!(fsm.run(&ProcReply::SentKey(0xe0)), Ok(Cmd::WaitForKey));
+
+        fsm.reset();
+        assert_eq!(fsm.run(&ProcReply::init()), Ok(Cmd::WaitForKey));
+        assert_eq!(fsm.run(&ProcReply::GrabbedKey(0x1f)), Ok(LONE_1F));
+    }
+
+    #[cfg(feature = "make-only")]
+    #[test]
+    fn make_only_releases_modifiers_on_request() {
+        let mut fsm = Fsm::start();
+        let cmds = drive(&mut fsm, &[
+            ProcReply::init(),
+            ProcReply::GrabbedKey(0x12), // Left Shift
+            ProcReply::SentKey(0x2a),
+            ProcReply::GrabbedKey(0xf0),
+        ]);
+        assert_eq!(cmds[3], Ok(Cmd::WaitForKey));
+
+        // Halfway through the break, nothing goes out.
+        let mut released = [0; 4];
+        let mut n : usize = 0;
+        fsm.release_modifiers(|k| { released[n] = k; n = n + 1; });
+        assert_eq!(n, 0);
+
+        // No break for Shift from the keyboard; only the one asked for.
+        assert_eq!(fsm.run(&ProcReply::GrabbedKey(0x12)), Ok(Cmd::WaitForKey));
+        fsm.release_modifiers(|k| { released[n] = k; n = n + 1; });
+        assert_eq!(&released[..n], &[0xaa]);
+
+        fsm.release_modifiers(|k| { released[n] = k; n = n + 1; });
+        assert_eq!(n, 1);
+    }
+
+    #[test]
+    fn reset_forgets_break_prefix() {
+        let mut fsm = Fsm::start();
+        assert_eq!(fsm.run(&ProcReply::init()), Ok(Cmd::WaitForKey));
+        assert_eq!(fsm.run(&ProcReply::GrabbedKey(0xf0)), Ok(Cmd::WaitForKey));
+
+        fsm.reset();
+        assert_eq!(fsm.run(&ProcReply::init()), Ok(Cmd::WaitForKey));
+        assert_eq!(fsm.run(&ProcReply::GrabbedKey(0x1c)), Ok(Cmd::SendXTKey(0x1e)));
+    }
+
+    // The injected command goes out in place of the next WaitForKey; the keycode that
+    // arrives meanwhile is still handled, just one trip later.
+    #[test]
+    fn inject_clear_buffer_mid_pause() {
+        let mut fsm = Fsm::start();
+        assert_eq!(fsm.run(&ProcReply::init()), Ok(Cmd::WaitForKey));
+        assert_eq!(fsm.run(&ProcReply::GrabbedKey(0xe1)), Ok(Cmd::WaitForKey));
+
+        fsm.inject(Cmd::ClearBuffer);
+        assert_eq!(fsm.run(&ProcReply::GrabbedKey(0x14)), Ok(Cmd::ClearBuffer));
+        assert_eq!(fsm.run(&ProcReply::ClearedBuffer), Ok(Cmd::WaitForKey));
+
+        // Still in the middle of Pause: the rest of the sequence completes it.
+        let cmds = drive(&mut fsm, &[
+            ProcReply::GrabbedKey(0x77),
+            ProcReply::GrabbedKey(0xe1),
+            ProcReply::GrabbedKey(0xf0),
+            ProcReply::GrabbedKey(0x14),
+            ProcReply::GrabbedKey(0xf0),
+            ProcReply::GrabbedKey(0x77),
+        ]);
+        assert_eq!(cmds[4], Ok(Cmd::WaitForKey));
+        assert_eq!(cmds[5], Ok(Cmd::SendXTSequence(&PAUSE_XT)));
+    }
+
+    // A key already on its way to the PC goes out first; the injected command waits for
+    // its reply, and the key's own follow-up waits for the injected one.
+    #[test]
+    fn inject_clear_buffer_after_current_command() {
+        let mut fsm = Fsm::start();
+        assert_eq!(fsm.run(&ProcReply::init()), Ok(Cmd::WaitForKey));
+        assert_eq!(fsm.run(&ProcReply::GrabbedKey(0x1c)), Ok(Cmd::SendXTKey(0x1e)));
+
+        fsm.inject(Cmd::ClearBuffer);
+        assert_eq!(fsm.run(&ProcReply::SentKey(0x1e)), Ok(Cmd::ClearBuffer));
+        assert_eq!(fsm.run(&ProcReply::ClearedBuffer), Ok(Cmd::WaitForKey));
+        assert_eq!(fsm.run(&ProcReply::GrabbedKey(0x32)), Ok(Cmd::SendXTKey(0x30)));
+    }
+
+    // A second inject before the first went out replaces it.
+    #[test]
+    fn inject_replaces_pending() {
+        let mut fsm = Fsm::start();
+        fsm.inject(Cmd::Identify);
+        fsm.inject(Cmd::ClearBuffer);
+        assert_eq!(fsm.run(&ProcReply::init()), Ok(Cmd::ClearBuffer));
+        assert_eq!(fsm.run(&ProcReply::ClearedBuffer), Ok(Cmd::WaitForKey));
+        assert_eq!(fsm.run(&ProcReply::GrabbedKey(0x1c)), Ok(Cmd::SendXTKey(0x1e)));
+    }
+
+    #[test]
+    fn reset_forgets_held_keys() {
+        let mut fsm = Fsm::start();
+        assert_eq!(fsm.run(&ProcReply::init()), Ok(Cmd::WaitForKey));
+        assert_eq!(fsm.run(&ProcReply::GrabbedKey(0x12)), Ok(Cmd::SendXTKey(0x2a)));
+
+        fsm.reset();
+        let mut released = 0;
+        fsm.release_held(|_| { released = released + 1; });
+        assert_eq!(released, 0);
+    }
+
+    #[test]
+    fn keyboard_error_clears_buffer() {
+        let mut fsm = Fsm::start();
+        let cmds = drive(&mut fsm, &[
+            ProcReply::init(),
+            ProcReply::GrabbedKey(0xe0),
+            ProcReply::KeyboardError,
+            ProcReply::ClearedBuffer,
+        ]);
+
+        assert_eq!(cmds[2], Ok(Cmd::ClearBuffer));
+        assert_eq!(cmds[3], Ok(Cmd::WaitForKey));
+    }
+
+    #[test]
+    fn keyboard_reset_clears_buffer() {
+        let mut fsm = Fsm::start();
+        assert_eq!(fsm.run(&ProcReply::init()), Ok(Cmd::WaitForKey));
+        assert_eq!(fsm.run(&ProcReply::KeyboardReset), Ok(Cmd::ClearBuffer));
+        assert_eq!(fsm.run(&ProcReply::ClearedBuffer), Ok(Cmd::WaitForKey));
+    }
+
+    // One of every state, as next_state() can leave it. Only a lock key reaches
+    // ToggleLedFirst, and which code that is depends on the scan set.
+    fn every_state(caps_lock : u8) -> [State; 19 + EXTRA_STATES] {
+        [
+            State::NotInKey,
+            State::SimpleKey(0x1e),
+            State::PossibleBreakCode,
+            State::KnownBreakCode(0x1e),
+            State::ExtendedPrefix,
+            State::ExtendedKey,
+            State::PossibleExtendedBreakCode,
+            State::ToggleLedFirst(caps_lock),
+            State::InPause(1),
+            State::InPause(4),
+            State::InPause(7),
+            State::PauseKey,
+            State::Inconsistent,
+            State::ExpectingBufferClear,
+            State::Resetting,
+            State::Identifying,
+            State::ResettingForHost,
+            State::SwitchingScanSet(2),
+            State::SwitchingScanSet(3),
+            #[cfg(feature = "trace")]
+            State::DumpingTrace,
+            #[cfg(feature = "wiring-test")]
+            State::WiringTest,
+            #[cfg(feature = "passthrough-unknown")]
+            State::UnknownKey(0x84),
+            #[cfg(feature = "use-timer")]
+            State::FlushingStale,
+        ]
+    }
+
+    const EXTRA_STATES : usize = cfg!(feature = "trace") as usize
+        + cfg!(feature = "wiring-test") as usize
+        + cfg!(feature = "passthrough-unknown") as usize
+        + cfg!(feature = "use-timer") as usize;
+
+    // Every reply but GrabbedKey, which is walked separately over every byte.
+    fn every_other_reply() -> [ProcReply; 15 + EXTRA_REPLIES] {
+        [
+            ProcReply::NothingToDo,
+            ProcReply::SentKey(0x1e),
+            ProcReply::SentSequence,
+            ProcReply::ClearedBuffer,
+            ProcReply::LedToggled(LED_CAPS),
+            ProcReply::KeyboardReset,
+            ProcReply::BadFrame,
+            ProcReply::KeyboardError,
+            ProcReply::DidReset,
+            ProcReply::Identified,
+            ProcReply::AtAck(0xed),
+            ProcReply::AtNak(0xed),
+            ProcReply::HostResetRequested,
+            ProcReply::ScanSetSelected(2),
+            ProcReply::ScanSetSelected(3),
+            #[cfg(feature = "trace")]
+            ProcReply::TraceDumped,
+            #[cfg(feature = "wiring-test")]
+            ProcReply::SentTestPattern,
+            #[cfg(feature = "use-timer")]
+            ProcReply::FlushedStale,
+        ]
+    }
+
+    const EXTRA_REPLIES : usize = cfg!(feature = "trace") as usize
+        + cfg!(feature = "wiring-test") as usize
+        + cfg!(feature = "use-timer") as usize;
+
+    // The pairs run() may return Err for: Inconsistent, and the list above the catch-all
+    // arm in next_state().
+    fn documented_err(state : &State, reply : &ProcReply) -> bool {
+        match (state, reply) {
+            (_, &ProcReply::HostResetRequested) => { false },
+            (_, &ProcReply::KeyboardReset) => { false },
+            (_, &ProcReply::KeyboardError) => { false },
+            (&State::Inconsistent, _) => { true },
+            (_, &ProcReply::BadFrame) => { false },
+            (_, &ProcReply::AtAck(_)) => { false },
+            (_, &ProcReply::AtNak(_)) => { false },
+            (&State::NotInKey, &ProcReply::NothingToDo) => { false },
+            (&State::NotInKey, &ProcReply::GrabbedKey(_)) => { false },
+            (&State::PossibleBreakCode, &ProcReply::GrabbedKey(_)) => { false },
+            (&State::ExtendedKey, &ProcReply::GrabbedKey(_)) => { false },
+            (&State::PossibleExtendedBreakCode, &ProcReply::GrabbedKey(_)) => { false },
+            (&State::InPause(_), &ProcReply::GrabbedKey(_)) => { false },
+            (&State::SimpleKey(_), &ProcReply::SentKey(_)) => { false },
+            (&State::KnownBreakCode(_), &ProcReply::SentKey(_)) => { false },
+            (&State::ExtendedPrefix, &ProcReply::SentKey(_)) => { false },
+            (&State::ToggleLedFirst(_), &ProcReply::LedToggled(_)) => { false },
+            (&State::PauseKey, &ProcReply::SentSequence) => { false },
+            (&State::ExpectingBufferClear, &ProcReply::ClearedBuffer) => { false },
+            (&State::Resetting, &ProcReply::DidReset) => { false },
+            (&State::Identifying, &ProcReply::Identified) => { false },
+            (&State::SwitchingScanSet(_), &ProcReply::ScanSetSelected(_)) => { false },
+            #[cfg(feature = "trace")]
+            (&State::DumpingTrace, &ProcReply::TraceDumped) => { false },
+            #[cfg(feature = "use-timer")]
+            (&State::FlushingStale, &ProcReply::FlushedStale) => { false },
+            #[cfg(feature = "wiring-test")]
+            (&State::WiringTest, &ProcReply::NothingToDo) => { false },
+            #[cfg(feature = "wiring-test")]
+            (&State::WiringTest, &ProcReply::SentTestPattern) => { false },
+            #[cfg(feature = "passthrough-unknown")]
+            (&State::UnknownKey(_), &ProcReply::SentKey(_)) => { false },
+            (_, _) => { true },
+        }
+    }
+
+    fn run_in(set : u8, state : State, reply : &ProcReply) -> Result<Cmd, ()> {
+        let mut fsm = Fsm::start();
+        fsm.set_scan_set(set);
+        fsm.curr_state = state;
+        fsm.run(reply)
+    }
+
+    // run() is total: every reply in every state, in both scan sets, comes back without a
+    // panic, and only the documented pairs come back Err.
+    #[test]
+    fn every_state_and_reply() {
+        for &(set, caps_lock) in [(2, 0x58), (3, 0x14)].iter() {
+            for &state in every_state(caps_lock).iter() {
+                for (i, reply) in every_other_reply().iter().enumerate() {
+                    let result = run_in(set, state, reply);
+                    assert_eq!(result.is_err(), documented_err(&state, reply),
+                        "set {}, {:?}, reply #{}: {:?}", set, state, i, result);
+                }
+                for k in 0..0x100u16 {
+                    let reply = ProcReply::GrabbedKey(k as u8);
+                    let result = run_in(set, state, &reply);
+                    assert_eq!(result.is_err(), documented_err(&state, &reply),
+                        "set {}, {:?}, GrabbedKey({:#x}): {:?}", set, state, k, result);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,85 @@
+// Turns the raw byte stream from the keyboard back into key events, for the keylog
+// feature. Deliberately knows nothing about scan sets or translation: a key with no XT
+// equivalent shows up here like any other.
+pub struct KeyLog {
+    prefix : u8, // 0xE0, 0xE1, or 0 for none.
+    is_break : bool,
+    // E1 prefixes a two-code key (Pause: E1 14 77, then E1 F0 14 F0 77). The first code
+    // names the event; the second is part of it, not a key of its own.
+    e1_second : bool,
+}
+
+impl KeyLog {
+    pub const fn new() -> KeyLog {
+        KeyLog {
+            prefix : 0,
+            is_break : false,
+            e1_second : false,
+        }
+    }
+
+    // The line to log for this byte, and the code to print after it; None while a prefix
+    // is still being collected, and for the second code of an E1 key.
+    pub fn feed(&mut self, code : u8) -> Option<(&'static [u8], u8)> {
+        match code {
+            0xe0 | 0xe1 => { self.prefix = code; None },
+            0xf0 => { self.is_break = true; None },
+            _ if self.e1_second => {
+                self.e1_second = false;
+                self.is_break = false;
+                None
+            },
+            _ => {
+                let msg : &'static [u8] = match (self.prefix, self.is_break) {
+                    (0xe0, false) => { b"E0 MAKE" },
+                    (0xe0, true) => { b"E0 BREAK" },
+                    (0xe1, false) => { b"E1 MAKE" },
+                    (0xe1, true) => { b"E1 BREAK" },
+                    (_, false) => { b"MAKE" },
+                    (_, true) => { b"BREAK" },
+                };
+
+                self.e1_second = self.prefix == 0xe1;
+                self.prefix = 0;
+                self.is_break = false;
+                Some((msg, code))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_all(log : &mut KeyLog, codes : &[u8]) -> [Option<(&'static [u8], u8)>; 8] {
+        let mut out = [None; 8];
+        for (i, c) in codes.iter().enumerate() {
+            out[i] = log.feed(*c);
+        }
+        out
+    }
+
+    #[test]
+    fn plain_and_e0_keys() {
+        let mut log = KeyLog::new();
+        let out = feed_all(&mut log, &[0x1c, 0xf0, 0x1c, 0xe0, 0x75, 0xe0, 0xf0, 0x75]);
+        assert_eq!(out[0], Some((&b"MAKE"[..], 0x1c)));
+        assert_eq!(out[1], None);
+        assert_eq!(out[2], Some((&b"BREAK"[..], 0x1c)));
+        assert_eq!(out[4], Some((&b"E0 MAKE"[..], 0x75)));
+        assert_eq!(out[7], Some((&b"E0 BREAK"[..], 0x75)));
+    }
+
+    // Pause is one make and one break, not Ctrl and Num Lock going up and down.
+    #[test]
+    fn pause_is_one_event_each_way() {
+        let mut log = KeyLog::new();
+        let out = feed_all(&mut log, &[0xe1, 0x14, 0x77, 0xe1, 0xf0, 0x14, 0xf0, 0x77]);
+        assert_eq!(out, [None, Some((&b"E1 MAKE"[..], 0x14)), None,
+                         None, None, Some((&b"E1 BREAK"[..], 0x14)), None, None]);
+
+        // And nothing left over for the next key.
+        assert_eq!(log.feed(0x14), Some((&b"MAKE"[..], 0x14)));
+    }
+}
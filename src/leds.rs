@@ -0,0 +1,77 @@
+#[cfg(target_arch = "msp430")]
+use msp430_atomic::AtomicU8;
+
+// Bit positions in the mask sent after the 0xED "Set LEDs" command. Everything that
+// names an LED goes through these, so a keyboard that wires them the other way around
+// only needs the invert-leds feature, which swaps Scroll Lock and Caps Lock.
+#[cfg(not(feature = "invert-leds"))]
+pub const LED_SCROLL : u8 = 1 << 0;
+#[cfg(not(feature = "invert-leds"))]
+pub const LED_CAPS : u8 = 1 << 2;
+#[cfg(feature = "invert-leds")]
+pub const LED_SCROLL : u8 = 1 << 2;
+#[cfg(feature = "invert-leds")]
+pub const LED_CAPS : u8 = 1 << 0;
+pub const LED_NUM : u8 = 1 << 1;
+pub const LED_ALL : u8 = LED_SCROLL | LED_NUM | LED_CAPS;
+// Three different bits, and the ones the keyboard reads.
+#[allow(dead_code)]
+const _LED_BITS_DISTINCT : [(); 0 - ((LED_ALL != 0x07) as usize)] = [];
+
+// Remembers which LEDs are lit, since the keyboard can't be asked. Only the idle context
+// touches this; the atomic just lets it live in a static. The host test build has no
+// MSP430 atomics, and only needs the bits above.
+#[cfg(target_arch = "msp430")]
+pub struct LedState {
+    mask : AtomicU8,
+}
+
+#[cfg(target_arch = "msp430")]
+impl LedState {
+    pub const fn new() -> LedState {
+        LedState {
+            mask : AtomicU8::new(0),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn mask(&self) -> u8 {
+        self.mask.load()
+    }
+
+    // Returns the new mask, ready to send to the keyboard.
+    pub fn toggle(&self, bits : u8) -> u8 {
+        let new_mask : u8 = self.mask.load() ^ bits;
+        self.mask.store(new_mask);
+        new_mask
+    }
+
+    // The keyboard turns all LEDs off when it resets.
+    pub fn clear(&self) -> () {
+        self.mask.store(0);
+    }
+
+    #[allow(dead_code)]
+    pub fn set_num_lock(&self, on : bool) -> () {
+        self.set_bits(LED_NUM, on);
+    }
+
+    #[allow(dead_code)]
+    pub fn set_caps_lock(&self, on : bool) -> () {
+        self.set_bits(LED_CAPS, on);
+    }
+
+    #[allow(dead_code)]
+    pub fn set_scroll_lock(&self, on : bool) -> () {
+        self.set_bits(LED_SCROLL, on);
+    }
+
+    fn set_bits(&self, bits : u8, on : bool) -> () {
+        let old_mask : u8 = self.mask.load();
+        if on {
+            self.mask.store(old_mask | bits);
+        } else {
+            self.mask.store(old_mask & !bits);
+        }
+    }
+}
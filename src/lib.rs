@@ -0,0 +1,23 @@
+// The half of the converter that doesn't touch the hardware, built as a library of its own
+// so it can be tested on the host with `make test`. The firmware in main.rs compiles the
+// same files as its own modules and doesn't link against this.
+#![no_std]
+#![feature(const_fn)]
+
+extern crate bit_reverse;
+
+#[cfg(target_arch = "msp430")]
+extern crate msp430g2211;
+#[cfg(target_arch = "msp430")]
+extern crate msp430_atomic;
+
+pub mod error;
+pub mod keybuffer;
+pub mod scancode;
+pub mod leds;
+pub mod keyfsm;
+pub mod atqueue;
+pub mod driver;
+pub mod keylog;
+#[cfg(all(feature = "sim", not(target_arch = "msp430")))]
+pub mod sim;
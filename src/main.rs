@@ -3,11 +3,11 @@
 #![feature(proc_macro)]
 #![feature(abi_msp430_interrupt)]
 #![feature(const_fn)]
+#![feature(lang_items)]
 
 extern crate msp430;
 
 extern crate bit_reverse;
-use bit_reverse::BitwiseReverse;
 
 extern crate msp430g2211;
 
@@ -16,22 +16,51 @@ extern crate msp430_rtfm as rtfm;
 use rtfm::app;
 
 extern crate msp430_atomic;
-use msp430_atomic::AtomicBool;
+use msp430_atomic::{AtomicBool, AtomicU8, AtomicU16};
+
+mod scancode;
 
 mod keyfsm;
 use keyfsm::{Cmd, ProcReply, Fsm};
 
 mod keybuffer;
-use keybuffer::{KeycodeBuffer, KeyIn, KeyOut};
+use keybuffer::{DefaultBuffer, DefaultOutBuffer, DefaultSequence, KeyIn, KeyOut, Slots,
+                XtFrameBits, XtOutSequence, XT_START_BITS};
 
 mod driver;
 use driver::KeyboardPins;
 
+mod error;
+use error::{AtError, HostBusy};
+
+mod leds;
+use leds::{LedState, LED_ALL};
+
+mod mode;
+
+mod atqueue;
+use atqueue::{AtCommand, AtCommandQueue};
+
+#[cfg(feature = "stats")]
+mod stats;
+#[cfg(feature = "stats")]
+use stats::Stats;
+
+#[cfg(feature = "keylog")]
+mod keylog;
+#[cfg(feature = "keylog")]
+use keylog::KeyLog;
+
+#[cfg(feature = "trace")]
+mod trace;
+#[cfg(feature = "trace")]
+use trace::{Trace, TRACE_LEN};
+
 #[cfg(feature = "use-timer")]
 macro_rules! us_to_ticks {
     ($u:expr) => {
-        // Timer is 100000 Hz, thus granularity of 10us.
-        ($u / 10) + 1
+        // Timer is TIMER_HZ (100000 Hz), thus granularity of 10us.
+        (((($u) as u32) * TIMER_HZ / 1_000_000) + 1) as u16
     }
 }
 
@@ -39,29 +68,232 @@ macro_rules! us_to_ticks {
 macro_rules! us_to_ticks {
     ($u:expr) => {
         // Delay is approx clock speed, thus granularity of 0.625us.
-        ($u * 16) / 10
+        // Intermediate is widened so delays up to ~40ms don't overflow.
+        (((($u) as u32) * (CLOCK_CONFIG.expected_hz / 100_000)) / 10) as u16
     }
 }
 
 
 #[cfg(feature = "use-timer")]
 static TIMEOUT : AtomicBool = AtomicBool::new(false);
-static HOST_MODE : AtomicBool = AtomicBool::new(false);
 static DEVICE_ACK : AtomicBool = AtomicBool::new(false);
+static REPLY_PENDING : AtomicBool = AtomicBool::new(false);
+static AT_REPLY : AtomicU8 = AtomicU8::new(0);
+static AT_REPLY_VALID : AtomicBool = AtomicBool::new(false);
+static LED_STATE : LedState = LedState::new();
+static KEYBOARD_ID : AtomicU16 = AtomicU16::new(0);
+static SCAN_SET : AtomicU8 = AtomicU8::new(2);
+// Set by the interrupt when it leaves the AT clock held low after a keycode, for the idle
+// loop to release.
+static AT_INHIBITED : AtomicBool = AtomicBool::new(false);
+// diag-as-keys: messages typed to the PC, DIAG_KEYS_BURST at most, after which nothing
+// more is typed until DIAG_KEYS_QUIET good keycodes have come in. A keyboard spewing bad
+// frames would otherwise bury whatever the user is typing.
+#[cfg(feature = "diag-as-keys")]
+const DIAG_KEYS_BURST : u8 = 3;
+#[cfg(feature = "diag-as-keys")]
+const DIAG_KEYS_QUIET : u8 = 64;
+#[cfg(feature = "diag-as-keys")]
+static DIAG_KEYS_LEFT : AtomicU8 = AtomicU8::new(DIAG_KEYS_BURST);
+#[cfg(feature = "diag-as-keys")]
+static DIAG_KEYS_GOOD : AtomicU8 = AtomicU8::new(0);
+// deadlock-detect: trips through the FSM with keys waiting in IN_BUFFER and none taken,
+// after which the FSM is assumed stuck and the keyboard side is reset. Every key takes a
+// handful at most, with a BadFrame or queued command or two in between.
+#[cfg(feature = "deadlock-detect")]
+const DEADLOCK_PASSES : u8 = 64;
+// Whether the wiring-test jumper was fitted at power-up.
+#[cfg(feature = "wiring-test")]
+static WIRING_TEST : AtomicBool = AtomicBool::new(false);
+// Set by the interrupt when it throws away a malformed frame.
+static RESEND_REQUESTED : AtomicBool = AtomicBool::new(false);
+// Most recent corrupt keycode frame, as shifted in (start bit in bit 10), for dump_trace.
+#[cfg(feature = "trace")]
+static LAST_BAD_FRAME : AtomicU16 = AtomicU16::new(0);
+// Set by the interrupt on a falling edge of xt_sense.
+static RESET_REQUESTED : AtomicBool = AtomicBool::new(false);
+// LED commands refused in a row, and whether we've given up on them until power-off.
+static LED_FAILURES : AtomicU8 = AtomicU8::new(0);
+static LED_DISABLED : AtomicBool = AtomicBool::new(false);
+// use-timer: KEY_CLOCK_TICKs of timer time so far, and what's left over toward the next.
+#[cfg(feature = "use-timer")]
+static KEY_CLOCK : AtomicU8 = AtomicU8::new(0);
+#[cfg(feature = "use-timer")]
+static KEY_CLOCK_SPENT : AtomicU16 = AtomicU16::new(0);
+// stats: counts for field reports, blinked out after the trace. A static rather than a
+// resource, so that leaving them out costs neither RAM nor a pair of app! variants.
+#[cfg(feature = "stats")]
+static STATS : Stats = Stats::new();
+
+// Pause between an ACKed 0xED and the LED mask, for keyboards that ACK before they're
+// ready for the argument.
+const AT_LED_GUARD_US : u16 = 500;
+// Some cheap keyboards never ACK 0xED (or hang for a while after it). Each attempt costs
+// a full ACK timeout, so after this many in a row the LEDs are left alone for good.
+const LED_FAILURES_BEFORE_DISABLE : u8 = 3;
+// Flow control for IN_BUFFER. The high-water mark is a single keycode: the interrupt
+// holds the keyboard off after every one. The idle loop lets go once taking a key leaves
+// no more than this many behind. Keys normally come in one at a time and there's nothing
+// behind; a backlog builds when a command to the keyboard has to let go of the lines
+// with keys still waiting, and this keeps the keyboard quiet until it's worked off. The
+// keyboard has a buffer of its own to hold keys in the meantime.
+const IN_BUFFER_LOW_WATER : usize = 0;
+// Keys already waiting in IN_BUFFER are translated back to back, up to this many, before
+// WaitForKey goes back to sending one queued byte to the PC per key. Each of those sends
+// takes about a millisecond, which a burst would otherwise pay once per key before its
+// last key even reached the FSM. OUT_BUFFER holds what piles up meanwhile, and what
+// doesn't fit goes out right away.
+const IN_BURST_MAX : u8 = 4;
+// make-only: modifiers the PC was told are down are let go once the keyboard has sent
+// nothing for this long. One that's really still held repeats its make code well before
+// then (the slowest typematic delay is 1s) and is pressed again; in set 3 nothing repeats,
+// so it's let go anyway. Counted in 1ms passes of the wait for a key, so it runs long by
+// whatever else each pass does.
+#[cfg(feature = "make-only")]
+const MODIFIER_TIMEOUT_MS : u16 = 1500;
+// Shortest inhibit the keyboard is guaranteed to notice. It only looks at the clock
+// between frames, so a pulse shorter than this can be missed altogether.
+const AT_INHIBIT_HOLD_US : u16 = 100;
+// Sent to reset the keyboard at startup, on a host reset, and on recovery. Must be a
+// command that ends in a self-test, since reset_keyboard() waits for the BAT code.
+const AT_RESET : u8 = 0xFF;
+// Resets tried at startup before signalling a problem on the LEDs (then trying again).
+const RESET_ATTEMPTS : u8 = 3;
+// Number of times a command byte is retransmitted if the keyboard asks for a resend.
+const AT_RESENDS : u8 = 3;
+// How long to wait for the keyboard to stop clocking before a transfer.
+const AT_CLK_TIMEOUT_US : u16 = 2000;
+// Poll interval of the non-timer wait_while. Short enough not to step over a whole AT
+// clock phase, which lasts 30us at the least.
+#[cfg(not(feature = "use-timer"))]
+const WAIT_POLL_TICKS : u16 = us_to_ticks!(10);
+// Keyboard has up to 15ms to start clocking after a request-to-send, and must
+// reply to a command within 20ms.
+const AT_ACK_TIMEOUT_US : u16 = 20000;
+// Self-test takes the keyboard 500-750ms after a reset. Waited for in AT_ACK_TIMEOUT_US
+// chunks, so this is about a second.
+const AT_BAT_WAITS : u8 = 50;
+// Typematic rate/delay applied at startup. Bits 6-5 are the delay in units of 250ms
+// (minus one), bits 4-0 the rate (0x00 is 30 cps, 0x1F is 2 cps). Bit 7 must be zero.
+const TYPEMATIC_DEFAULT : u8 = 0x00; // 30 cps, 250ms delay.
+// Busy-loop time measured by the calibrate and boot-selfcheck features. Long enough for
+// 10us timer ticks to give a couple of digits, short enough not to need the watchdog
+// petted.
+#[cfg(any(feature = "calibrate", feature = "boot-selfcheck"))]
+const CALIBRATE_US : u32 = 10_000;
+// How far off the measured busy loop may be before boot-selfcheck refuses to start.
+#[cfg(feature = "boot-selfcheck")]
+const DELAY_TOLERANCE_PCT : u32 = 25;
+// Consecutive low samples of xt_sense, XT_SENSE_SAMPLE_US apart, needed to believe the
+// host really wants a reset. Hosts hold the line low for milliseconds; noise doesn't.
+const XT_SENSE_DEBOUNCE : u8 = 4;
+const XT_SENSE_SAMPLE_US : u16 = 100;
+// poweron-settle: how long to leave the keyboard alone after power-up before the first
+// command. Some keyboards ignore (or half take) a reset sent during their own power-on
+// self-test, and only a replug brings them back.
+#[cfg(feature = "poweron-settle")]
+const POWERON_SETTLE_MS : u32 = 500;
+// Past this, a PC that resets us at power-up would give up before we answer.
+#[cfg(feature = "poweron-settle")]
+#[allow(dead_code)]
+const _POWERON_SETTLE_BOUND : [(); 0 - ((POWERON_SETTLE_MS > 2000) as usize)] = [];
+// How long the host may keep holding the clock low after we've seen a reset, in 20ms
+// chunks. Real hosts let go after about 20ms.
+const XT_RESET_RELEASE_WAITS : u8 = 25;
+// How long send_byte_to_pc waits for the host to let go of the lines before giving up on
+// this pass. A host busy with the last byte lets go in well under a millisecond, and a
+// reset lasts about 20ms (and is dealt with by the idle loop, not here). Anything longer
+// is a host that's off, or has disabled the keyboard.
+const XT_HOST_BUSY_TIMEOUT_US : u16 = 25000;
+
+// How long xt_clk is held low for each bit sent to the PC. Genuine IBM hardware and clone
+// BIOSes don't all agree on what's acceptable, so this is the knob to try first when a
+// converter works on one motherboard but not another.
+pub struct XtTiming {
+    half_bit_us : u16, // Data bits.
+    start_us : u16, // The two start bits.
+}
+
+const DEFAULT_XT_TIMING : XtTiming = XtTiming { half_bit_us : 55, start_us : 55 };
+
+// Blinked out on the Num Lock LED at startup by the version-blink feature. Unrelated to the
+// crate version; it only needs to tell builds apart in the field, so bump it on any release.
+#[cfg(feature = "version-blink")]
+const FIRMWARE_VERSION : u8 = 1;
+
+// DCO and submain clock setup. init() programs the clock from this, and every delay and
+// timeout is derived from it, so a board running at a different speed only changes it here.
+pub struct ClockConfig {
+    rsel : u8, // DCO range select, 0-15.
+    divs : u8, // SMCLK is MCLK divided by 1 << divs, 0-3.
+    expected_hz : u32, // MCLK this produces. The DCO isn't calibrated, so this is measured.
+}
+
+const CLOCK_CONFIG : ClockConfig = ClockConfig { rsel : 8, divs : 2, expected_hz : 1_600_000 };
+
+// Timer A runs from SMCLK, divided by 4 again.
+#[cfg(feature = "use-timer")]
+const TIMER_HZ : u32 = (CLOCK_CONFIG.expected_hz >> CLOCK_CONFIG.divs) / 4;
+
+// Timer mode assumes 10us ticks: the longest timeout must still fit in a u16 count. Fails
+// to compile (subtraction overflow) if CLOCK_CONFIG no longer gives a 100000 Hz timer.
+#[cfg(feature = "use-timer")]
+const _TIMER_HZ_CHECK : [(); 0 - ((TIMER_HZ != 100_000) as usize)] = [];
+
+// use-timer: IN_BUFFER stamps keys with KEY_CLOCK, which ticks every 10ms of timer time.
+// The timer only runs while the idle loop is timing something, so this isn't wall-clock
+// time: waiting for a key doesn't advance it, while a reset, which is nothing but waits
+// for the keyboard, does. That's the time FlushStale cares about.
+#[cfg(feature = "use-timer")]
+const KEY_CLOCK_TICK : u16 = 1000;
+// use-timer: keys that waited in IN_BUFFER longer than this through a reset are dropped
+// once it's over (Cmd::FlushStale). Well past a keystroke that just happened to land at
+// the end; well short of KEY_CLOCK wrapping around on an old one.
+#[cfg(feature = "use-timer")]
+const STALE_KEY_TICKS : u8 = 25;
+
+// A delay that rounds down to zero ticks isn't short, it's broken: the asm delay loop
+// decrements before testing, so zero wraps around to 65535. Check every fixed delay we use
+// at build time, in whichever timer configuration is selected.
+macro_rules! assert_ticks_nonzero {
+    ($name:ident, $u:expr) => {
+        #[allow(dead_code)]
+        const $name : [(); 0 - ((us_to_ticks!($u) == 0) as usize)] = [];
+    }
+}
+
+assert_ticks_nonzero!(_XT_BIT_TICKS, DEFAULT_XT_TIMING.half_bit_us);
+assert_ticks_nonzero!(_XT_START_TICKS, DEFAULT_XT_TIMING.start_us);
+assert_ticks_nonzero!(_XT_SENSE_TICKS, XT_SENSE_SAMPLE_US);
+assert_ticks_nonzero!(_AT_INHIBIT_TICKS, AT_INHIBIT_HOLD_US);
+assert_ticks_nonzero!(_AT_RTS_TICKS, 33);
+assert_ticks_nonzero!(_AT_LED_GUARD_TICKS, AT_LED_GUARD_US);
+
+// Bit time of the debug-uart log on P1.5 (8N1). 10us timer ticks can't hit 9600 baud
+// closely enough, so timer builds log at 4800.
+#[cfg(all(feature = "debug-uart", not(feature = "use-timer")))]
+const DEBUG_UART_BIT_US : u16 = 104;
+#[cfg(all(feature = "debug-uart", feature = "use-timer"))]
+const DEBUG_UART_BIT_US : u16 = 208;
+
+// Upper byte of every write to wdtctl.
+const WDT_PASSWORD : u16 = 0x5A00;
 
 #[cfg(not(feature = "use-timer"))]
 app! {
     device: msp430g2211,
 
     idle: {
-        resources: [KEYBOARD_PINS, PORT_1_2, IN_BUFFER, KEY_IN, KEY_OUT],
+        resources: [KEYBOARD_PINS, PORT_1_2, WATCHDOG_TIMER, IN_BUFFER, OUT_BUFFER, KEY_IN, KEY_OUT, AT_QUEUE],
     },
 
     resources: {
-        static IN_BUFFER : KeycodeBuffer = KeycodeBuffer::new();
+        static IN_BUFFER : DefaultBuffer = DefaultBuffer::new();
+        static OUT_BUFFER : DefaultOutBuffer = DefaultOutBuffer::new();
         static KEYBOARD_PINS : KeyboardPins = KeyboardPins::new();
         static KEY_IN : KeyIn = KeyIn::new();
         static KEY_OUT : KeyOut = KeyOut::new();
+        static AT_QUEUE : AtCommandQueue = AtCommandQueue::new();
     },
 
     tasks: {
@@ -76,14 +308,16 @@ app! {
     device: msp430g2211,
 
     idle: {
-        resources: [KEYBOARD_PINS, TIMER_A2, PORT_1_2, IN_BUFFER, KEY_IN, KEY_OUT],
+        resources: [KEYBOARD_PINS, TIMER_A2, PORT_1_2, WATCHDOG_TIMER, IN_BUFFER, OUT_BUFFER, KEY_IN, KEY_OUT, AT_QUEUE],
     },
 
     resources: {
-        static IN_BUFFER : KeycodeBuffer = KeycodeBuffer::new();
+        static IN_BUFFER : DefaultBuffer = DefaultBuffer::new();
+        static OUT_BUFFER : DefaultOutBuffer = DefaultOutBuffer::new();
         static KEYBOARD_PINS : KeyboardPins = KeyboardPins::new();
         static KEY_IN : KeyIn = KeyIn::new();
         static KEY_OUT : KeyOut = KeyOut::new();
+        static AT_QUEUE : AtCommandQueue = AtCommandQueue::new();
     },
 
     tasks: {
@@ -98,12 +332,33 @@ app! {
 }
 
 
+// Replaces msp430-rt's abort-on-panic. A panic in the middle of a frame could leave
+// XT_CLK or AT_CLK held low; a PC keyboard controller that sees a stuck clock may not
+// recover without a reboot, so release everything before stopping. The resources can't be
+// borrowed from here, but KeyboardPins has no state of its own, so a fresh copy will do.
+// With the watchdog feature on, the chip resets itself a moment later.
+#[lang = "panic_fmt"]
+#[no_mangle]
+pub extern fn panic_fmt(_args : core::fmt::Arguments, _file : &'static str, _line : u32) -> ! {
+    msp430::interrupt::disable();
+
+    let port = unsafe { &*msp430g2211::PORT_1_2.get() };
+    KeyboardPins::new().force_idle_all(port);
+
+    loop {
+
+    }
+}
+
+
 #[cfg(feature = "use-timer")]
 task!(TIMERA0, timer0_handler);
 #[cfg(feature = "use-timer")]
 fn timer0_handler(r: TIMERA0::Resources) {
     let timer = r.TIMER_A2;
     TIMEOUT.store(true);
+    // The compare just hit, so the whole period has gone by.
+    advance_key_clock(timer.taccr0.read().bits());
 
     // Writing 0x0000 stops Timer in MC1.
     timer.taccr0.write(|w| unsafe { w.bits(0x0000) });
@@ -115,46 +370,111 @@ fn timer0_handler(r: TIMERA0::Resources) {
 
 task!(PORT1, porta_handler);
 fn porta_handler(r: PORT1::Resources) {
-    if HOST_MODE.load() {
-        if !r.KEY_OUT.is_empty() {
-            if r.KEY_OUT.shift_out() {
-                r.KEYBOARD_PINS.at_data.set(&r.PORT_1_2);
-            } else{
-                r.KEYBOARD_PINS.at_data.unset(&r.PORT_1_2);
-            }
+    nest_enter();
+    handle_port1(r);
+    nest_exit();
+}
 
-            // Immediately after sending out the Stop Bit, we should release the lines.
-            if r.KEY_OUT.is_empty() {
-                r.KEYBOARD_PINS.at_idle(r.PORT_1_2);
-            }
-        } else {
-            if r.KEYBOARD_PINS.at_data.is_unset(r.PORT_1_2) {
-                DEVICE_ACK.store(true);
-                r.KEY_OUT.clear();
-            }
+fn handle_port1(r: PORT1::Resources) {
+    if r.KEYBOARD_PINS.xt_sense_int_pending(r.PORT_1_2) {
+        RESET_REQUESTED.store(true);
+        r.KEYBOARD_PINS.clear_xt_sense_int(r.PORT_1_2);
+    }
+
+    if !r.KEYBOARD_PINS.at_clk_int_pending(r.PORT_1_2) {
+        return;
+    }
+
+    if mode::is_host_mode() {
+        let stop_bit : bool = r.KEY_OUT.is_in_stop_bit();
+
+        match r.KEY_OUT.shift_out() {
+            Some(bit) => { r.KEYBOARD_PINS.at_data_write(&r.PORT_1_2, bit); },
+            // Frame is out. Only the first clock after the stop bit is the ACK clock; any
+            // other clock with nothing to send is noise, and never counts as an ACK.
+            None => {
+                if r.KEY_OUT.take_ack_clock() && at_data_held_low(&r.KEYBOARD_PINS, r.PORT_1_2) {
+                    DEVICE_ACK.store(true);
+                    r.KEY_OUT.clear();
+                } else {
+                    // No ACK where one should be: the keyboard counted a different number
+                    // of clocks than we did. Let go of the lines rather than guess; the idle
+                    // context will time out waiting for DEVICE_ACK and clean up.
+                    r.KEYBOARD_PINS.at_idle(r.PORT_1_2);
+                }
+            },
+        }
+
+        // Immediately after sending out the Stop Bit, we should release the lines so
+        // the keyboard can pull data low for the ACK on the next clock.
+        if stop_bit {
+            r.KEYBOARD_PINS.at_idle(r.PORT_1_2);
         }
 
         r.KEYBOARD_PINS.clear_at_clk_int(r.PORT_1_2);
     } else {
         let full : bool;
 
-        // Are the buffer functions safe in nested interrupts? Is it possible to use tokens/manual
-        // sync for nested interrupts while not giving up safety?
-        // Example: Counter for nest level when updating buffers. If it's ever more than one, panic.
+        // The buffer functions aren't safe in nested interrupts. Nothing here turns
+        // interrupts back on, so that can't happen; debug-checks makes sure (nest_enter).
         r.KEY_IN.shift_in(r.KEYBOARD_PINS.at_data.is_set(r.PORT_1_2));
         full = r.KEY_IN.is_full();
 
         if full {
             r.KEYBOARD_PINS.at_inhibit(r.PORT_1_2); // Ask keyboard to not send anything while processing keycode.
 
-            match r.KEY_IN.take() {
-                Some(k) => { r.IN_BUFFER.put(k); },
+            // Also catches a bit-slip; the clear() below resyncs us to the next start bit.
+            let valid = r.KEY_IN.is_valid_frame();
+            let was_reply = REPLY_PENDING.load();
+
+            match r.KEY_IN.take_raw() {
+                // The first frame after a host-to-device transfer is the keyboard's reply to
+                // the command; it is handed directly to the waiting idle context instead of
+                // being treated as a keycode.
+                // Replies are checked by the waiting context, which knows what to do about
+                // a bad one. Bad keycodes are dropped here and the idle loop asks the
+                // keyboard for them again.
+                Some((k, raw)) => {
+                    #[cfg(not(feature = "trace"))]
+                    let _ = raw;
+
+                    #[cfg(feature = "stats")]
+                    {
+                        STATS.count_frame();
+                        if !valid {
+                            STATS.count_parity_error();
+                        }
+                    }
+
+                    if REPLY_PENDING.load() {
+                        AT_REPLY.store(k);
+                        AT_REPLY_VALID.store(valid);
+                        REPLY_PENDING.store(false);
+                    } else if !valid {
+                        #[cfg(feature = "trace")]
+                        LAST_BAD_FRAME.store(raw);
+                        RESEND_REQUESTED.store(true);
+                    } else {
+                        #[cfg(feature = "use-timer")]
+                        r.IN_BUFFER.set_clock(KEY_CLOCK.load());
+                        // A full buffer counts the loss itself (see dropped()).
+                        let _ = r.IN_BUFFER.put(k as u16);
+                    }
+                },
                 None => { },
             }
 
             r.KEY_IN.clear();
 
-            r.KEYBOARD_PINS.at_idle(r.PORT_1_2);
+            // The inhibit is only a few microseconds old, which the keyboard may not even
+            // see. Rather than wait here, leave it to the idle loop, which lets go once it
+            // has taken the key (see release_at_inhibit). Whoever is waiting for a reply
+            // usually wants the next byte too, so that doesn't wait.
+            if was_reply {
+                r.KEYBOARD_PINS.at_idle(r.PORT_1_2);
+            } else {
+                AT_INHIBITED.store(true);
+            }
         }
 
         r.KEYBOARD_PINS.clear_at_clk_int(r.PORT_1_2);
@@ -162,18 +482,104 @@ fn porta_handler(r: PORT1::Resources) {
 }
 
 
+// debug-checks: porta_handler runs with interrupts off, so it can never be entered again
+// before it returns. If it were (an RTFM change that let tasks nest, say), KEY_IN and
+// KEY_OUT could be updated halfway through an update and garble a frame without a trace.
+// Count the handlers running and panic, which leaves the lines idle, on a second one.
+#[cfg(feature = "debug-checks")]
+static NEST_LEVEL : AtomicU8 = AtomicU8::new(0);
+
+#[cfg(feature = "debug-checks")]
+fn nest_enter() -> () {
+    let level = NEST_LEVEL.load() + 1;
+    NEST_LEVEL.store(level);
+    if level > 1 {
+        panic!();
+    }
+}
+
+#[cfg(feature = "debug-checks")]
+fn nest_exit() -> () {
+    NEST_LEVEL.store(NEST_LEVEL.load() - 1);
+}
+
+#[cfg(not(feature = "debug-checks"))]
+fn nest_enter() -> () {
+
+}
+
+#[cfg(not(feature = "debug-checks"))]
+fn nest_exit() -> () {
+
+}
+
+// The ACK, as the keyboard sends it once it has clocked in our stop bit:
+//
+//              stop bit    ACK
+//  AT_CLK   ~~~\_____/~~~~\_____/~~~~~~
+//  AT_DATA  ~~~~~~~~~~~~~\________/~~~~  (released by us after the stop bit)
+//
+// Data goes low 5-25us before the 11th falling clock edge and stays low until after the
+// clock rises again, 30-50us later. By the time this interrupt runs, the line has been low
+// for a while and will stay low for a while yet, so every read in a short burst should see
+// it low. A glitch on the released line (pulled up only weakly) can't manage that.
+const AT_ACK_SAMPLES : u8 = 3;
+
+fn at_data_held_low(pins : &KeyboardPins, port : &msp430g2211::PORT_1_2) -> bool {
+    for _ in 0..AT_ACK_SAMPLES {
+        if pins.at_data.is_set(port) {
+            return false;
+        }
+    }
+
+    true
+}
+
 fn init(p: init::Peripherals, r: init::Resources) {
+    #[cfg(not(feature = "watchdog"))]
     p.WATCHDOG_TIMER.wdtctl.write(|w| unsafe {
-        const PASSWORD: u16 = 0x5A00;
-        w.bits(PASSWORD).wdthold().set_bit()
+        w.bits(WDT_PASSWORD).wdthold().set_bit()
+    });
+
+    // Watchdog mode, SMCLK/32768: about 82ms at our 400kHz SMCLK, rather than the ~250ms
+    // we'd like. Nothing gets closer. SMCLK/32768 is the longest interval SMCLK gives, and
+    // with no crystal on the board, ACLK would have to come from the VLO (4-20kHz), where
+    // VLO/8192 lands anywhere between 0.4s and 2s and VLO/512 is shorter still. So every
+    // wait has to fit in 82ms between pets. The long ones pet as they go: wait_while once
+    // per pass, delay_with_pet every 20ms, the debug UART once per byte. What's left
+    // unpetted is short: the inhibit hold and XT bit delays (100us and less), a whole XT
+    // byte (about 1ms), the calibrate loop (10ms). A hang anywhere resets in under 82ms.
+    #[cfg(feature = "watchdog")]
+    p.WATCHDOG_TIMER.wdtctl.write(|w| unsafe {
+        w.bits(WDT_PASSWORD).wdtcntcl().set_bit()
     });
 
     // Make port idle
     r.KEYBOARD_PINS.idle(p.PORT_1_2);
+    // Read at the end of init, to give the pull-up time.
+    #[cfg(feature = "wiring-test")]
+    r.KEYBOARD_PINS.pull_up_test_jumper(p.PORT_1_2);
+    // UART idles high.
+    #[cfg(feature = "debug-uart")]
+    {
+        r.KEYBOARD_PINS.debug_tx.set(p.PORT_1_2);
+        r.KEYBOARD_PINS.debug_tx.mk_out(p.PORT_1_2);
+    }
+    #[cfg(feature = "heartbeat")]
+    {
+        r.KEYBOARD_PINS.heartbeat.unset(p.PORT_1_2);
+        r.KEYBOARD_PINS.heartbeat.mk_out(p.PORT_1_2);
+    }
+    // Interrupts aren't enabled until init returns.
+    r.KEYBOARD_PINS.clear_xt_sense_int(p.PORT_1_2);
+    unsafe {
+        r.KEYBOARD_PINS.enable_xt_sense_int(p.PORT_1_2);
+    }
 
-    p.SYSTEM_CLOCK.bcsctl1.write(|w| w.xt2off().set_bit()
-        .rsel3().set_bit()); // XT2 off, Range Select 7.
-    p.SYSTEM_CLOCK.bcsctl2.write(|w| w.divs().divs_2()); // Divide submain clock by 4.
+    // XT2 off (bit 7), DCO range in bits 3-0.
+    p.SYSTEM_CLOCK.bcsctl1.write(|w| unsafe { w.bits(0x80 | (CLOCK_CONFIG.rsel & 0x0F)) });
+    // Submain clock divider in bits 2-1.
+    p.SYSTEM_CLOCK.bcsctl2.write(|w| unsafe { w.bits((CLOCK_CONFIG.divs & 0x03) << 1) });
 
     #[cfg(feature = "use-timer")]
     {
@@ -182,86 +588,619 @@ fn init(p: init::Peripherals, r: init::Resources) {
             .id().id_2().mc().mc_1());
         p.TIMER_A2.tacctl0.write(|w| w.ccie().set_bit());
     }
+
+    #[cfg(feature = "wiring-test")]
+    WIRING_TEST.store(r.KEYBOARD_PINS.test_jumper_fitted(p.PORT_1_2));
 }
 
 fn idle(mut r: idle::Resources) -> ! {
-    send_byte_to_at_keyboard(&mut r, 0xFF);
+    // Before anything is timed by the loop it checks.
+    #[cfg(feature = "boot-selfcheck")]
+    delay_self_check(&mut r);
+
+    #[cfg(feature = "poweron-settle")]
+    poweron_settle(&mut r);
+
+    // wiring-test: with the jumper fitted there may be no keyboard at all, and nothing
+    // but the test pattern is wanted. Leave the keyboard alone.
+    #[cfg(feature = "wiring-test")]
+    let keyboard_wanted : bool = !WIRING_TEST.load();
+    #[cfg(not(feature = "wiring-test"))]
+    let keyboard_wanted : bool = true;
+
+    if keyboard_wanted {
+        start_keyboard(&mut r);
+    }
+
+    #[cfg(feature = "calibrate")]
+    report_delay_calibration(&mut r);
 
     let mut loop_cmd : Cmd;
     let mut loop_reply : ProcReply = ProcReply::init();
     let mut fsm_driver : Fsm = Fsm::start();
+    fsm_driver.set_scan_set(SCAN_SET.load());
+    #[cfg(feature = "wiring-test")]
+    {
+        if WIRING_TEST.load() {
+            fsm_driver.start_wiring_test();
+        }
+    }
+    // Lives here rather than in a resource; nothing but the idle loop ever looks at it.
+    #[cfg(feature = "trace")]
+    let mut trace : Trace = Trace::new();
+    #[cfg(feature = "keylog")]
+    let mut keylog : KeyLog = KeyLog::new();
+    // Keys taken in a row without stopping to send the PC anything; see IN_BURST_MAX.
+    let mut burst : u8 = 0;
+    // make-only: how long the keyboard has been quiet; see MODIFIER_TIMEOUT_MS.
+    #[cfg(feature = "make-only")]
+    let mut quiet_ms : u16 = 0;
+    #[cfg(feature = "deadlock-detect")]
+    let mut stalled : u8 = 0;
 
     'get_command: loop {
+        heartbeat(&mut r);
+        // Some command keeps getting answered without WaitForKey ever running, or
+        // WaitForKey keeps finding something better to do than take a key. No reply
+        // will get the FSM out of that; start both sides over, as for an Err below.
+        #[cfg(feature = "deadlock-detect")]
+        {
+            if deadlock_suspected(&mut r, &mut stalled, &loop_reply) {
+                debug_log(&mut r, b"DEADLOCK", None);
+                release_held_keys(&mut r, &mut fsm_driver);
+                soft_reset(&mut r);
+                fsm_driver.reset();
+                loop_reply = ProcReply::init();
+                continue 'get_command;
+            }
+        }
         // Run state machine/send reply. Receive new cmd.
-        loop_cmd = fsm_driver.run(&loop_reply).unwrap();
+        loop_cmd = match fsm_driver.run(&loop_reply) {
+            Ok(cmd) => { cmd },
+            // The FSM got a reply it has no transition for. Start it over rather than
+            // stopping the converter; at worst one keystroke is lost.
+            // The trace stops here, so what led up to it is still there to blink out
+            // with TRACE_CHORD later.
+            Err(_) => {
+                #[cfg(feature = "trace")]
+                trace.freeze();
+
+                release_held_keys(&mut r, &mut fsm_driver);
+                fsm_driver.reset();
+                loop_reply = ProcReply::init();
+                continue 'get_command;
+            },
+        };
 
         loop_reply = match loop_cmd {
             Cmd::ClearBuffer => {
+                // Whatever the keyboard lost may have included the release of a key.
+                release_held_keys(&mut r, &mut fsm_driver);
                 rtfm::atomic(|cs| {
                     r.IN_BUFFER.borrow_mut(cs).flush();
                 });
+                // Nothing left to take, so the usual release in WaitForKey won't happen.
+                release_at_inhibit(&mut r);
                 ProcReply::ClearedBuffer
             },
             Cmd::ToggleLed(m) => {
-                toggle_leds(&mut r, m);
-                ProcReply::LedToggled(m)
+                let mask = LED_STATE.toggle(m);
+                // Sent in turn from WaitForKey, so it can't land in the middle of another
+                // command's ACK.
+                queue_at_command(&mut r, AtCommand::new(0xED, Some(mask)));
+                ProcReply::LedToggled(mask)
             }
             Cmd::SendXTKey(k) => {
-                send_byte_to_pc(&mut r, k);
+                queue_byte_to_pc(&mut r, k);
+                ProcReply::SentKey(k)
+            },
+            #[cfg(feature = "passthrough-unknown")]
+            Cmd::SendUnknownKey(k) => {
+                debug_log(&mut r, b"UNKNOWN", Some(k));
+                queue_byte_to_pc(&mut r, k);
                 ProcReply::SentKey(k)
             },
-            Cmd::WaitForKey => {
+            Cmd::SendXTSequence(s) => {
+                match DefaultSequence::from_slice(s) {
+                    Ok(seq) => {
+                        // Keys queued before this one go first.
+                        while !rtfm::atomic(|cs| { r.OUT_BUFFER.borrow(cs).is_empty() }) {
+                            drain_byte_to_pc(&mut r);
+                        }
+
+                        // The host is resetting us, so the half we sent doesn't matter; let
+                        // WaitForKey see the reset and answer it.
+                        if send_xt_sequence(&mut r, &seq).is_err() {
+                            debug_log(&mut r, b"XT PARTIAL", None);
+                            RESET_REQUESTED.store(true);
+                        }
+                    },
+                    // Too long to send in one go; the old way is better than nothing.
+                    Err(_) => { queue_sequence_to_pc(&mut r, s); },
+                }
+                ProcReply::SentSequence
+            },
+            Cmd::Reset => {
+                release_held_keys(&mut r, &mut fsm_driver);
+                diag_keys(&mut r, b"reset\n");
+                soft_reset(&mut r);
+                ProcReply::DidReset
+            },
+            Cmd::HostReset => {
+                debug_log(&mut r, b"HOST RESET", None);
+                #[cfg(feature = "stats")]
+                STATS.count_host_reset();
+                // The host gets its BAT code regardless; a keyboard that didn't take
+                // the reset is still usable.
+                let _ = send_byte_to_at_keyboard(&mut r, AT_RESET);
+                // Keys typed before the reset are meaningless to the host now.
+                rtfm::atomic(|cs| {
+                    r.OUT_BUFFER.borrow_mut(cs).flush();
+                });
+                // A host still holding the clock after all that isn't resetting
+                // us, it has disabled the keyboard. Don't block on it; it'll pull
+                // the clock again when it wants us back.
+                if wait_for_xt_reset_release(&mut r).is_ok() {
+                    let _ = send_byte_to_pc(&mut r, 0xAA, &DEFAULT_XT_TIMING);
+                }
+                ProcReply::KeyboardReset
+            },
+            Cmd::SetScanSet(set) => {
+                let old : u8 = SCAN_SET.load();
+                let now : u8 = if select_scan_set(&mut r, set).is_ok() {
+                    // Same as at startup: set 3 needs a break code from every key.
+                    if set == 3 {
+                        let _ = set_all_keys_mode(&mut r);
+                    }
+                    set
+                } else {
+                    // Whatever a half-taken command left it in, go back to what we know.
+                    let _ = select_scan_set(&mut r, old);
+                    old
+                };
+                SCAN_SET.store(now);
+                debug_log(&mut r, b"SCAN SET", Some(now));
+                ProcReply::ScanSetSelected(now)
+            },
+            #[cfg(feature = "wiring-test")]
+            Cmd::XtTestPattern => {
+                send_test_pattern(&mut r);
+                ProcReply::SentTestPattern
+            },
+            #[cfg(feature = "use-timer")]
+            Cmd::FlushStale => {
+                let room : bool = rtfm::atomic(|cs| {
+                    let in_buffer = r.IN_BUFFER.borrow_mut(cs);
+                    in_buffer.set_clock(KEY_CLOCK.load());
+                    in_buffer.flush_older_than(STALE_KEY_TICKS);
+                    in_buffer.len() <= IN_BUFFER_LOW_WATER
+                });
+                // As in ClearBuffer; WaitForKey only lets go after taking a key.
+                if room {
+                    release_at_inhibit(&mut r);
+                }
+                ProcReply::FlushedStale
+            },
+            #[cfg(feature = "trace")]
+            Cmd::DumpTrace => {
+                dump_trace(&mut r, &trace);
+                trace.thaw();
+                ProcReply::TraceDumped
+            },
+            Cmd::Identify => {
+                // A keyboard that won't say is typed as FFFF; no real keyboard uses that
+                // ID. The original 84-key AT has no ID and comes out as 0000.
+                let id = match read_keyboard_id(&mut r) {
+                    Ok(id) => { KEYBOARD_ID.store(id); id },
+                    Err(_) => { 0xFFFF },
+                };
+                type_hex_to_pc(&mut r, id);
+                ProcReply::Identified
+            },
+            Cmd::WaitForKey => loop {
                 // The micro spends the majority of its life idle. It is possible for the host PC and
                 // the keyboard to send data to the micro at the same time. To keep control flow simple,
-                // the micro will only respond to host PC acknowledge requests if its idle.
-                let mut xt_reset : bool = false;
-                'idle: while rtfm::atomic(|cs| { r.IN_BUFFER.borrow(cs).is_empty() }) {
-                    // If host computer wants to reset
-                    if rtfm::atomic(|cs| {
-                        r.KEYBOARD_PINS.borrow(cs)
-                            .xt_sense.is_unset(r.PORT_1_2.borrow(cs))
-                    }) {
-                        send_byte_to_at_keyboard(&mut r, 0xFF);
-                        send_byte_to_pc(&mut r, 0xAA);
-                        xt_reset = true;
+                // the micro will only respond to host PC reset requests in between keyboard frames.
+                // Keys queued for the PC are sent one at a time in between.
+                // This spins rather than sleeping in LPM0. Waking from LPM0 means clearing
+                // CPUOFF in the SR the interrupt stacked, and the handlers generated by task!
+                // give us no way to reach it; setting CPUOFF here would never return.
+
+                // While keys are backed up, skip the send, up to IN_BURST_MAX times in a
+                // row. Queued commands wait out a burst too. Resends and host resets don't:
+                // the checks cost next to nothing, and both matter more than a few keys'
+                // latency.
+                if burst < IN_BURST_MAX && !rtfm::atomic(|cs| { r.IN_BUFFER.borrow(cs).is_empty() }) {
+                    burst = burst + 1;
+                } else {
+                    burst = 0;
+                    drain_byte_to_pc(&mut r);
+                    if let Some(reply) = drain_at_command(&mut r) {
+                        break reply;
+                    }
+                }
+                if request_resend_if_needed(&mut r) {
+                    debug_log(&mut r, b"BAD FRAME", None);
+                    diag_keys(&mut r, b"parity\n");
+                    break ProcReply::BadFrame;
+                }
+                // Ahead of any keys still buffered: the FSM is between frames here, and
+                // it services the reset before flushing whatever is left.
+                if host_reset_requested(&mut r) {
+                    break ProcReply::HostResetRequested;
+                }
+
+                let mut early_reply : Option<ProcReply> = None;
+                while rtfm::atomic(|cs| { r.IN_BUFFER.borrow(cs).is_empty() }) {
+                    pet_watchdog(&mut r);
+                    heartbeat(&mut r);
+                    drain_byte_to_pc(&mut r);
+                    if let Some(reply) = drain_at_command(&mut r) {
+                        early_reply = Some(reply);
+                        break;
+                    }
+                    if request_resend_if_needed(&mut r) {
+                        debug_log(&mut r, b"BAD FRAME", None);
+                        diag_keys(&mut r, b"parity\n");
+                        early_reply = Some(ProcReply::BadFrame);
+                        break;
+                    }
+
+                    if host_reset_requested(&mut r) {
+                        early_reply = Some(ProcReply::HostResetRequested);
                         break;
                     }
+                    #[cfg(feature = "make-only")]
+                    {
+                        quiet_ms = release_quiet_modifiers(&mut r, &mut fsm_driver, quiet_ms);
+                    }
                 }
 
-                if xt_reset {
-                    ProcReply::KeyboardReset
-                } else {
-                    let mut bits_in = rtfm::atomic(|cs|{
-                        match r.IN_BUFFER.borrow_mut(cs).take() {
-                            Some(k) => { k },
-                            None => { 0 },
-                        }
-                    });
+                if let Some(reply) = early_reply {
+                    break reply;
+                }
+
+                let (key, room) = rtfm::atomic(|cs|{
+                    let k = match r.IN_BUFFER.borrow_mut(cs).take() {
+                        Some(k) => { k },
+                        None => { 0 },
+                    };
+
+                    (k, r.IN_BUFFER.borrow(cs).len() <= IN_BUFFER_LOW_WATER)
+                });
+
+                // Let the keyboard resume, unless there's a backlog to work off first.
+                if room {
+                    release_at_inhibit(&mut r);
+                }
+                #[cfg(feature = "make-only")]
+                {
+                    quiet_ms = 0;
+                }
 
-                    bits_in = bits_in & !(0x4000 + 0x0001); // Mask out start/stop bit.
-                    bits_in = bits_in >> 2; // Remove stop bit and parity bit (FIXME: Check parity).
-                    ProcReply::GrabbedKey((bits_in as u8).swap_bits())
+                // Replies to commands never get this far, so these can only be the
+                // keyboard's overrun codes (0x00 in sets 2 and 3, 0xFF in set 1).
+                debug_log(&mut r, b"RX", Some(key as u8));
+                diag_keys_good_frame();
+                // Before the FSM sees it, so keys it would drop are still logged.
+                #[cfg(feature = "keylog")]
+                {
+                    if let Some((msg, code)) = keylog.feed(key as u8) {
+                        debug_log(&mut r, msg, Some(code));
+                    }
+                }
+                match key as u8 {
+                    0x00 | 0xFF => { break ProcReply::KeyboardError; },
+                    k => { break ProcReply::GrabbedKey(k); },
                 }
             },
 
+        };
+
+        #[cfg(feature = "trace")]
+        trace.record(&loop_cmd, &loop_reply);
+    }
+}
+
+// Everything between power-up and the main loop that involves the keyboard: wait for it
+// to come up, reset it, and set it up the way the FSM expects.
+fn start_keyboard(r: &mut idle::Resources) -> () {
+    // A keyboard that's plugged in after we power up, or is slow to come out of its own
+    // power-on reset, can sit with the clock low for seconds. Nothing can be sent until it
+    // lets go, and there's no way to tell anyone why: the only LEDs are on the keyboard.
+    while wait_for_at_bus_idle(r).is_err() {
+        // Make sure it's not us holding the bus.
+        rtfm::atomic(|cs| {
+            r.KEYBOARD_PINS.borrow(cs)
+                .at_idle(r.PORT_1_2.borrow(cs));
+        });
+    }
+
+    // With skip-reset, a keyboard that's already up and answering is left as it is (LEDs
+    // and all, though our lock state still starts out clear). If it doesn't answer, it gets
+    // the usual reset.
+    #[cfg(feature = "skip-reset")]
+    let needs_reset : bool = read_keyboard_id(r).is_err();
+    #[cfg(not(feature = "skip-reset"))]
+    let needs_reset : bool = true;
+
+    if needs_reset {
+        // Keyboard may still be running its power-on self-test (or not be plugged in yet);
+        // keep trying until it acknowledges the reset and passes the self-test that
+        // follows. Anything sent before then would be ignored.
+        'reset: loop {
+            let mut present : bool = false;
+
+            for attempt in 0..RESET_ATTEMPTS {
+                match reset_keyboard(r) {
+                    Ok(()) => { break 'reset; },
+                    Err(AtError::NoKeyboard) => { },
+                    Err(_) => { present = true; },
+                }
+
+                // Slow-booting keyboards get a little longer each time.
+                delay_with_pet(r, 100_000 * ((attempt as u32) + 1));
+            }
+
+            // A keyboard that ACKs but fails its self-test will still light its LEDs. One
+            // that isn't there can't, so say so on the status pin instead.
+            if present {
+                flash_leds(r, LED_ALL);
+            } else {
+                blink_no_keyboard(r);
+            }
+        }
+    }
+    // Neither is fatal. No ID means an 84-key keyboard, which never sends E0 codes anyway,
+    // and a failed typematic command leaves the power-on repeat rate.
+    KEYBOARD_ID.store(read_keyboard_id(r).unwrap_or(0));
+
+    // Don't rely on the power-on default. IBM terminal keyboards (IDs 0xBFxx) only speak
+    // set 3, and a keyboard that refuses set 2 is assumed to be one of them.
+    if (KEYBOARD_ID.load() >> 8) != 0xBF && select_scan_set(r, 2).is_ok() {
+        SCAN_SET.store(2);
+    } else {
+        let _ = select_scan_set(r, 3);
+        // Out of the box, set 3 leaves some keys make-only. The FSM expects a break code
+        // for every key, as in set 2. A keyboard that NAKs this (one that isn't really in
+        // set 3) keeps its defaults.
+        let _ = set_all_keys_mode(r);
+        SCAN_SET.store(3);
+    }
+    // Reset and scan set selection above go out directly, since what happens next depends
+    // on the answer. Nothing depends on this one; it goes out from the main loop.
+    let _ = set_typematic(r, TYPEMATIC_DEFAULT);
+
+    #[cfg(feature = "diagnostics")]
+    {
+        // All three LEDs for a good echo, Caps Lock alone for a bad one. No flash at all
+        // means the keyboard isn't listening.
+        match diagnostic_echo(r) {
+            Ok(true) => { flash_leds(r, LED_ALL); },
+            Ok(false) => { flash_leds(r, leds::LED_CAPS); },
+            Err(_) => { },
+        }
+    }
+
+    #[cfg(feature = "version-blink")]
+    blink_version(r);
+}
+
+// wiring-test: an A pressed and released, once a second. On a working XT output stage the
+// PC prints an "a" each time. Sent directly, since nothing else is waiting to go out, and
+// a host that isn't listening just misses one.
+#[cfg(feature = "wiring-test")]
+const XT_TEST_PATTERN : [u8; 2] = [0x1e, 0x9e];
+
+#[cfg(feature = "wiring-test")]
+fn send_test_pattern(r: &mut idle::Resources) -> () {
+    for b in XT_TEST_PATTERN.iter() {
+        let _ = send_byte_to_pc(r, *b, &DEFAULT_XT_TIMING);
+    }
+
+    delay_with_pet(r, 1_000_000);
+}
+
+// Count a trip through the FSM towards DEADLOCK_PASSES, or start over if reply is a key
+// taken from IN_BUFFER or there's nothing in it to take.
+#[cfg(feature = "deadlock-detect")]
+fn deadlock_suspected(r: &mut idle::Resources, stalled : &mut u8, reply : &ProcReply) -> bool {
+    // The test pattern never takes keys, whatever the keyboard sends.
+    #[cfg(feature = "wiring-test")]
+    {
+        if WIRING_TEST.load() {
+            return false;
+        }
+    }
+
+    let waiting : bool = !rtfm::atomic(|cs| { r.IN_BUFFER.borrow(cs).is_empty() });
+    *stalled = match reply {
+        &ProcReply::GrabbedKey(_) | &ProcReply::KeyboardError => { 0 },
+        _ if waiting => { stalled.saturating_add(1) },
+        _ => { 0 },
+    };
+
+    if *stalled >= DEADLOCK_PASSES {
+        *stalled = 0;
+        true
+    } else {
+        false
+    }
+}
+
+// make-only: 1ms more of waiting for a key, for a total of quiet_ms. The modifiers are let
+// go the moment that reaches MODIFIER_TIMEOUT_MS, and not again until a key has come in.
+#[cfg(feature = "make-only")]
+fn release_quiet_modifiers(r: &mut idle::Resources, fsm : &mut Fsm, quiet_ms : u16) -> u16 {
+    delay(r, us_to_ticks!(1000));
+    let quiet_ms : u16 = quiet_ms.saturating_add(1);
+    if quiet_ms == MODIFIER_TIMEOUT_MS {
+        fsm.release_modifiers(|k| { queue_byte_to_pc(r, k); });
+    }
+    quiet_ms
+}
+
+// Queued rather than sent, so they go out behind anything the PC hasn't had yet.
+fn release_held_keys(r: &mut idle::Resources, fsm : &mut Fsm) -> () {
+    fsm.release_held(|k| { queue_byte_to_pc(r, k); });
+}
+
+// If host computer wants to reset. The edge alone could be noise, so make sure the line
+// is still held.
+fn host_reset_requested(r: &mut idle::Resources) -> bool {
+    RESET_REQUESTED.load() && {
+        RESET_REQUESTED.store(false);
+        xt_sense_asserted(r)
+    }
+}
+
+fn xt_sense_asserted(r: &mut idle::Resources) -> bool {
+    for _ in 0..XT_SENSE_DEBOUNCE {
+        if !rtfm::atomic(|cs| {
+            r.KEYBOARD_PINS.borrow(cs)
+                .xt_sense.is_unset(r.PORT_1_2.borrow(cs))
+        }) {
+            return false;
+        }
+
+        delay(r, us_to_ticks!(XT_SENSE_SAMPLE_US));
+    }
+
+    true
+}
+
+// Give the keyboard about a second (AT_BAT_WAITS chunks) to release the AT bus.
+fn wait_for_at_bus_idle(r: &mut idle::Resources) -> Result<(), AtError> {
+    for _ in 0..AT_BAT_WAITS {
+        let idle = wait_while(r, us_to_ticks!(AT_ACK_TIMEOUT_US), |r : &mut idle::Resources| {
+            !rtfm::atomic(|cs| {
+                r.KEYBOARD_PINS.borrow(cs)
+                    .at_bus_idle(r.PORT_1_2.borrow(cs))
+            })
+        });
+
+        if idle.is_ok() {
+            return Ok(());
         }
     }
+
+    Err(AtError::ClockTimeout)
+}
+
+// Host resets are answered only once the host lets go of the clock; several XT BIOSes
+// don't look for the BAT code until then, and miss it if it's early.
+//
+//   xt_clk  ----+                 +--------------------+ +-+ +- ...
+//               |                 |                    | | | |
+//               +-----------------+                    +-+ +-+
+//               ^    ^            ^                    ^
+//               |    |            host releases;       0xAA
+//               |    debounced,   this returns Ok
+//               |    AT keyboard reset
+//               RESET_REQUESTED
+fn wait_for_xt_reset_release(r: &mut idle::Resources) -> Result<(), AtError> {
+    for _ in 0..XT_RESET_RELEASE_WAITS {
+        let released = wait_while(r, us_to_ticks!(20000), |r : &mut idle::Resources| {
+            rtfm::atomic(|cs| {
+                r.KEYBOARD_PINS.borrow(cs)
+                    .xt_sense.is_unset(r.PORT_1_2.borrow(cs))
+            })
+        });
+
+        if released.is_ok() {
+            return Ok(());
+        }
+    }
+
+    Err(AtError::ClockTimeout)
 }
 
-pub fn send_xt_bit(r: &mut idle::Resources, bit : u8) -> () {
+// Let go of an inhibit the interrupt left in place, if there is one. It's held for at least
+// AT_INHIBIT_HOLD_US first; most of that has usually gone by already, but nothing else
+// guarantees it.
+fn release_at_inhibit(r: &mut idle::Resources) -> () {
+    if !AT_INHIBITED.load() {
+        return;
+    }
+
+    delay(r, us_to_ticks!(AT_INHIBIT_HOLD_US));
+    rtfm::atomic(|cs| {
+        AT_INHIBITED.store(false);
+        r.KEYBOARD_PINS.borrow(cs)
+            .at_idle(r.PORT_1_2.borrow(cs));
+    });
+}
+
+// A corrupt frame is never forwarded to the PC. Ask the keyboard to send it again; the
+// retransmitted frame will show up in IN_BUFFER like any other. Returns whether a resend
+// was needed, so the FSM can notice when they keep happening.
+fn request_resend_if_needed(r: &mut idle::Resources) -> bool {
+    if RESEND_REQUESTED.load() {
+        RESEND_REQUESTED.store(false);
+        #[cfg(feature = "stats")]
+        STATS.count_resend();
+        let _ = send_byte_to_at_keyboard_raw(r, 0xFE);
+        true
+    } else {
+        false
+    }
+}
+
+// Put the AT side back the way idle() found it at power-on, without touching the XT side.
+// Whatever the interrupt was in the middle of receiving is thrown away.
+fn soft_reset(r: &mut idle::Resources) -> () {
     rtfm::atomic(|cs| {
         let pins = r.KEYBOARD_PINS.borrow(cs);
         let port = r.PORT_1_2.borrow(cs);
-        if bit == 1 {
-            pins.xt_data.set(port);
-        } else {
-            pins.xt_data.unset(port);
+
+        r.IN_BUFFER.borrow_mut(cs).flush();
+        // Whatever was queued was meant for the keyboard as it was before the reset.
+        r.AT_QUEUE.borrow_mut(cs).flush();
+        r.KEY_IN.borrow_mut(cs).clear();
+        r.KEY_OUT.borrow_mut(cs).clear();
+        pins.at_idle(port);
+        pins.clear_at_clk_int(port);
+
+        unsafe {
+            pins.enable_at_clk_int(port);
         }
 
+        mode::exit_host_mode();
+        REPLY_PENDING.store(false);
+        AT_INHIBITED.store(false);
+        RESEND_REQUESTED.store(false);
+    });
+
+    LED_STATE.clear();
+    // Not fatal. If the keyboard missed it and keeps sending garbage, we end up back here.
+    let _ = reset_keyboard(r);
+    // A keyboard that didn't take the reset may still take this, and one that did ignores
+    // nothing by getting it twice. Either way the scan set is back to 2 now.
+    let _ = set_defaults(r);
+    restore_keyboard_config(r);
+}
+
+// Put back what startup chose, which a reset or 0xF6 throws away.
+fn restore_keyboard_config(r: &mut idle::Resources) -> () {
+    let set : u8 = SCAN_SET.load();
+    let _ = select_scan_set(r, set);
+    if set == 3 {
+        let _ = set_all_keys_mode(r);
+    }
+    let _ = set_typematic(r, TYPEMATIC_DEFAULT);
+}
+
+#[cfg_attr(feature = "use-timer", allow(dead_code))]
+pub fn send_xt_bit(r: &mut idle::Resources, bit : u8, low_us : u16) -> () {
+    rtfm::atomic(|cs| {
+        let pins = r.KEYBOARD_PINS.borrow(cs);
+        let port = r.PORT_1_2.borrow(cs);
+        pins.xt_data_write(port, bit == 1);
+
         pins.xt_clk.unset(port);
     });
 
-    delay(r, us_to_ticks!(55));
+    delay(r, us_to_ticks!(low_us));
 
     rtfm::atomic(|cs| {
         r.KEYBOARD_PINS.borrow(cs)
@@ -269,51 +1208,262 @@ pub fn send_xt_bit(r: &mut idle::Resources, bit : u8) -> () {
     });
 }
 
-pub fn send_byte_to_pc(r: &mut idle::Resources, mut byte : u8) -> () {
+pub fn send_byte_to_pc(r: &mut idle::Resources, byte : u8, timing : &XtTiming)
+    -> Result<(), HostBusy> {
     // The host cannot send data; the only communication it can do with the micro is pull
     // the CLK (reset) and DATA (shift register full) low.
-    // Wait for the host to release the lines.
+    // Wait for the host to release the lines, but not forever: a PC that's switched off
+    // holds them low for good, and the keyboard still needs servicing meanwhile. A reset
+    // held through the wait is still seen, since xt_sense is left enabled until we
+    // start driving the lines ourselves.
+    let released = wait_while(r, us_to_ticks!(XT_HOST_BUSY_TIMEOUT_US), |r : &mut idle::Resources| {
+        rtfm::atomic(|cs| {
+            let pins = r.KEYBOARD_PINS.borrow(cs);
+            let port = r.PORT_1_2.borrow(cs);
+            pins.xt_clk.is_unset(port) || pins.xt_data.is_unset(port)
+        })
+    });
+
+    if released.is_err() {
+        return Err(HostBusy);
+    }
 
-    while rtfm::atomic(|cs| {
+    // Our own clock pulses would look like a reset request.
+    rtfm::atomic(|cs| {
         let pins = r.KEYBOARD_PINS.borrow(cs);
         let port = r.PORT_1_2.borrow(cs);
-        pins.xt_clk.is_unset(port) || pins.xt_data.is_unset(port)
-    }) { }
+        pins.disable_xt_sense_int(port);
+        pins.xt_out(port);
+    });
+
+    #[cfg(not(feature = "use-timer"))]
+    {
+        for (i, bit) in XtFrameBits::new(byte).enumerate() {
+            let half_bit_us = if (i as u8) < XT_START_BITS {
+                timing.start_us
+            } else {
+                timing.half_bit_us
+            };
+            send_xt_bit(r, bit, half_bit_us);
+        }
+    }
+
+    #[cfg(feature = "use-timer")]
+    {
+        start_xt_bit_clock(r, us_to_ticks!(timing.start_us));
+        for (i, bit) in XtFrameBits::new(byte).enumerate() {
+            if i as u8 == XT_START_BITS {
+                set_xt_bit_clock(r, us_to_ticks!(timing.half_bit_us));
+            }
+            send_xt_bit_timed(r, bit);
+        }
+        stop_xt_bit_clock(r);
+    }
 
     rtfm::atomic(|cs| {
-        r.KEYBOARD_PINS.borrow(cs).xt_out(r.PORT_1_2.borrow(cs));
+        let pins = r.KEYBOARD_PINS.borrow(cs);
+        let port = r.PORT_1_2.borrow(cs);
+        pins.xt_in(port);
+        pins.clear_xt_sense_int(port);
+
+        unsafe {
+            pins.enable_xt_sense_int(port);
+        }
     });
 
-    send_xt_bit(r, 0);
-    send_xt_bit(r, 1);
+    Ok(())
+}
 
-    for _ in 0..8 {
-        send_xt_bit(r, byte & 0x01); /* Send data... */
-		byte = byte >> 1;
+// XT output is queued so that a slow host doesn't hold up the FSM. If OUT_BUFFER fills up,
+// the oldest byte is sent synchronously to make room. Meanwhile the keyboard keeps filling
+// IN_BUFFER from the interrupt, so a stalled host eventually shows up as backpressure on
+// the AT side rather than as lost XT codes. Only a host that stops listening altogether
+// (see XT_HOST_BUSY_TIMEOUT_US) loses bytes, newest first.
+pub fn queue_byte_to_pc(r: &mut idle::Resources, byte : u8) -> () {
+    if rtfm::atomic(|cs| { r.OUT_BUFFER.borrow(cs).is_full() }) {
+        drain_byte_to_pc(r);
     }
 
+    // Still full only if the host isn't listening.
     rtfm::atomic(|cs| {
-        r.KEYBOARD_PINS.borrow(cs).xt_in(r.PORT_1_2.borrow(cs));
+        let _ = r.OUT_BUFFER.borrow_mut(cs).put(byte);
     });
 }
 
-fn send_byte_to_at_keyboard(r: &mut idle::Resources, byte : u8) -> () {
+pub fn queue_sequence_to_pc(r: &mut idle::Resources, seq : &[u8]) -> () {
+    for b in seq {
+        queue_byte_to_pc(r, *b);
+    }
+}
+
+// Send seq right away, bypassing OUT_BUFFER, and stop early if the host pulls the clock
+// low for a reset in between bytes. send_byte_to_pc turns the sense interrupt off while
+// it transmits, so a reset that starts mid-sequence would otherwise go unnoticed until the
+// whole thing was out. Err holds how many bytes made it; a host that stopped listening
+// altogether ends the sequence the same way.
+fn send_xt_sequence<S : Slots<Item = u8>>(r: &mut idle::Resources, seq : &XtOutSequence<S>) -> Result<(), u8> {
+    let mut sent : u8 = 0;
+
+    for b in seq.as_slice() {
+        if xt_sense_asserted(r) {
+            return Err(sent);
+        }
+
+        if send_byte_to_pc(r, *b, &DEFAULT_XT_TIMING).is_err() {
+            return Err(sent);
+        }
+        sent = sent + 1;
+    }
+
+    Ok(())
+}
+
+// Type the four hex digits of val, most significant first, as make/break pairs.
+fn type_hex_to_pc(r: &mut idle::Resources, val : u16) -> () {
+    for shift in [12, 8, 4, 0].iter() {
+        let key : u8 = scancode::XT_HEX_DIGITS[((val >> *shift) & 0x0F) as usize];
+        queue_byte_to_pc(r, key);
+        queue_byte_to_pc(r, key | 0x80);
+    }
+}
+
+// A byte the host wasn't ready for stays at the front of OUT_BUFFER, to be tried again on
+// the next pass.
+fn drain_byte_to_pc(r: &mut idle::Resources) -> () {
+    let next = rtfm::atomic(|cs| { r.OUT_BUFFER.borrow(cs).peek() });
+
+    if let Some(b) = next {
+        if send_byte_to_pc(r, b, &DEFAULT_XT_TIMING).is_ok() {
+            rtfm::atomic(|cs| { let _ = r.OUT_BUFFER.borrow_mut(cs).take(); });
+        }
+    }
+}
+
+// Commands that don't need their answer right away go through AT_QUEUE and are sent one
+// per pass through WaitForKey, each waiting for its ACK as usual. Like OUT_BUFFER, a full
+// queue makes room by sending the oldest command now rather than dropping one.
+fn queue_at_command(r: &mut idle::Resources, cmd : AtCommand) -> () {
+    if rtfm::atomic(|cs| { r.AT_QUEUE.borrow(cs).is_full() }) {
+        // Nobody to tell how it went; same as a refused command sent from WaitForKey.
+        let _ = drain_at_command(r);
+    }
+
+    // Can't fail; there's guaranteed to be room by now.
+    rtfm::atomic(|cs| {
+        let _ = r.AT_QUEUE.borrow_mut(cs).put(cmd);
+    });
+}
+
+// Returns how the keyboard answered, for the FSM, or None if the queue was empty.
+fn drain_at_command(r: &mut idle::Resources) -> Option<ProcReply> {
+    let next = rtfm::atomic(|cs| { r.AT_QUEUE.borrow_mut(cs).take() });
+
+    let (cmd, res) = match next {
+        Some(AtCommand { cmd : 0xED, arg : Some(m) }) => { (0xED, toggle_leds(r, m)) },
+        Some(c) => { (c.cmd, send_at_command(r, c)) },
+        None => { return None; },
+    };
+
+    // A refused command isn't retried. For LEDs, the lock state is still toggled, and
+    // the LEDs resync the next time a lock key is pressed.
+    match res {
+        Ok(()) => { Some(ProcReply::AtAck(cmd)) },
+        Err(_) => { Some(ProcReply::AtNak(cmd)) },
+    }
+}
+
+fn send_at_command(r: &mut idle::Resources, cmd : AtCommand) -> Result<(), AtError> {
+    send_byte_to_at_keyboard(r, cmd.cmd)?;
+
+    match cmd.arg {
+        Some(a) => {
+            // Only 0xED strictly needs the gap, but it costs nothing elsewhere.
+            delay_with_pet(r, AT_LED_GUARD_US as u32);
+            send_byte_to_at_keyboard(r, a)
+        },
+        None => { Ok(()) },
+    }
+}
+
+fn send_byte_to_at_keyboard(r: &mut idle::Resources, byte : u8) -> Result<(), AtError> {
+    match send_byte_for_reply(r, byte)? {
+        0xFA => { Ok(()) },
+        _ => { Err(AtError::NoAck) },
+    }
+}
+
+// Send a byte, retransmitting for as long as the keyboard asks for a resend, and return
+// whatever it finally replied with. Almost every command is answered with an ACK; use
+// send_byte_to_at_keyboard for those.
+fn send_byte_for_reply(r: &mut idle::Resources, byte : u8) -> Result<u8, AtError> {
+    let mut err : AtError = AtError::NoAck;
+
+    for _ in 0..(AT_RESENDS + 1) {
+        send_byte_to_at_keyboard_raw(r, byte)?;
+
+        // The reply can't start until well after the device ACK, and the interrupt only
+        // checks REPLY_PENDING once a full frame is in, so there's no race here.
+        match wait_for_at_reply(r) {
+            // A reply that itself arrived corrupt is as good as a resend request.
+            Err(AtError::ParityError) => { err = AtError::ParityError; },
+            Err(e) => { return Err(e); },
+            Ok(0xFE) => { err = AtError::NoAck; },
+            Ok(k) => { return Ok(k); },
+        }
+    }
+
+    Err(err)
+}
+
+// Divert the next frame from the keyboard away from IN_BUFFER and return it.
+fn wait_for_at_reply(r: &mut idle::Resources) -> Result<u8, AtError> {
+    REPLY_PENDING.store(true);
+    wait_while(r, us_to_ticks!(AT_ACK_TIMEOUT_US), |_| { REPLY_PENDING.load() })
+        .map_err(|_| {
+            REPLY_PENDING.store(false);
+            AtError::NoAck
+        })?;
+
+    if AT_REPLY_VALID.load() {
+        Ok(AT_REPLY.load())
+    } else {
+        Err(AtError::ParityError)
+    }
+}
+
+// Send a byte without waiting for the keyboard to reply. Only appropriate when the reply
+// is not a response code, i.e. when asking the keyboard to resend its last keycode.
+fn send_byte_to_at_keyboard_raw(r: &mut idle::Resources, byte : u8) -> Result<(), AtError> {
     rtfm::atomic(|cs| {
         let key_out = r.KEY_OUT.borrow_mut(cs);
-        key_out.put(byte).unwrap();
-        // Safe outside of critical section: As long as HOST_MODE is
+        key_out.put(byte).map_err(|_| AtError::BufferFull)?;
+        // Safe outside of critical section: As long as host mode is
         // not set, it's not possible for the interrupt
         // context to touch this variable.
         r.KEYBOARD_PINS.borrow(cs)
             .disable_at_clk_int(r.PORT_1_2.borrow(cs));
-    });
+        Ok(())
+    })?;
 
     /* If/when timer int is enabled, this loop really needs to allow preemption during
     I/O read. Can it be done without overhead of CriticalSection? */
-    while rtfm::atomic(|cs| {
-        r.KEYBOARD_PINS.borrow(cs)
-            .at_clk.is_unset(r.PORT_1_2.borrow(cs))
-    }) { }
+    // Don't start until the keyboard has finished any frame it's sending. If we're the ones
+    // holding the clock low, there's no frame to wait for.
+    wait_while(r, us_to_ticks!(AT_CLK_TIMEOUT_US), |r : &mut idle::Resources| {
+        !AT_INHIBITED.load() && !rtfm::atomic(|cs| {
+            r.KEYBOARD_PINS.borrow(cs)
+                .at_bus_idle(r.PORT_1_2.borrow(cs))
+        })
+    }).map_err(|e| {
+        rtfm::atomic(|cs| {
+            r.KEY_OUT.borrow_mut(cs).clear();
+            unsafe {
+                r.KEYBOARD_PINS.borrow(cs)
+                    .enable_at_clk_int(r.PORT_1_2.borrow(cs));
+            }
+        });
+        e
+    })?;
 
 
     rtfm::atomic(|cs| {
@@ -321,43 +1471,325 @@ fn send_byte_to_at_keyboard(r: &mut idle::Resources, byte : u8) -> () {
             .at_inhibit(r.PORT_1_2.borrow(cs));
     });
 
-    delay(r, us_to_ticks!(100));
+    delay(r, us_to_ticks!(AT_INHIBIT_HOLD_US));
 
+    // Start bit. It's always zero; shifting it out of KEY_OUT just lines the interrupt up
+    // with the first data bit.
     rtfm::atomic(|cs| {
+        let _ = r.KEY_OUT.borrow_mut(cs).shift_out();
         r.KEYBOARD_PINS.borrow(cs)
-            .at_data.unset(r.PORT_1_2.borrow(cs));
+            .at_request_to_send(r.PORT_1_2.borrow(cs));
     });
 
     delay(r, us_to_ticks!(33));
 
     rtfm::atomic(|cs| {
-        let pins = r.KEYBOARD_PINS.borrow(cs);
-        let port = r.PORT_1_2.borrow(cs);
-        pins.at_clk.set(port);
-        pins.at_clk.mk_in(port);
-        pins.clear_at_clk_int(port);
-
         unsafe {
-            pins.enable_at_clk_int(port);
+            r.KEYBOARD_PINS.borrow(cs)
+                .begin_host_transmission(r.PORT_1_2.borrow(cs));
         }
-        HOST_MODE.store(true);
+        mode::enter_host_mode();
         DEVICE_ACK.store(false);
+        // The clock has just been let go, so any inhibit the interrupt left is over.
+        AT_INHIBITED.store(false);
     });
 
-    while !DEVICE_ACK.load() { }
+    let ack = wait_while(r, us_to_ticks!(AT_ACK_TIMEOUT_US), |_| { !DEVICE_ACK.load() });
+
+    // Keyboard went away mid-transfer; abandon the frame and give the lines back.
+    if ack.is_err() {
+        debug_log(r, b"ACK TIMEOUT", Some(byte));
+        diag_keys(r, b"ack timeout\n");
+        #[cfg(feature = "stats")]
+        STATS.count_ack_timeout();
+        rtfm::atomic(|cs| {
+            r.KEY_OUT.borrow_mut(cs).clear();
+            r.KEYBOARD_PINS.borrow(cs)
+                .at_idle(r.PORT_1_2.borrow(cs));
+        });
+    }
+
+    mode::exit_host_mode();
+    ack
+}
+
+// Reset the keyboard and wait for its self-test to finish.
+fn reset_keyboard(r: &mut idle::Resources) -> Result<(), AtError> {
+    // A keyboard that's there clocks the command in even if it then does nothing with it.
+    send_byte_to_at_keyboard(r, AT_RESET).map_err(|e| {
+        match e {
+            AtError::ClockTimeout => { AtError::NoKeyboard },
+            e => { e },
+        }
+    })?;
+
+    for _ in 0..AT_BAT_WAITS {
+        let reply = match wait_for_at_reply(r) {
+            Ok(reply) => { reply },
+            Err(AtError::ParityError) => { return Err(AtError::ParityError); },
+            Err(_) => { continue; },
+        };
+
+        match reply {
+            0xAA => { return Ok(()); },
+            0xFC | 0xFD => { return Err(AtError::SelfTestFailed); },
+            _ => { },
+        }
+    }
+
+    Err(AtError::ClockTimeout)
+}
+
+// The mask only goes out once 0xED has been ACKed; that's what the keyboard is waiting for,
+// not a fixed amount of time.
+// Once LED_DISABLED is latched this does nothing; lock keys still work, the keyboard just
+// doesn't show them.
+fn toggle_leds(r: &mut idle::Resources, mask : u8) -> Result<(), AtError> {
+    if LED_DISABLED.load() {
+        return Ok(());
+    }
+
+    let res = send_at_command(r, AtCommand::new(0xED, Some(mask)));
+    if res.is_ok() {
+        LED_FAILURES.store(0);
+    } else {
+        let failures = LED_FAILURES.load().saturating_add(1);
+        LED_FAILURES.store(failures);
+        if failures >= LED_FAILURES_BEFORE_DISABLE {
+            LED_DISABLED.store(true);
+            debug_log(r, b"LEDS DISABLED", None);
+        }
+    }
+
+    res
+}
+
+// Returns the two ID bytes, first byte in the high half. MF2 keyboards return 0xAB83.
+// The original 84-key AT keyboard only ACKs, which shows up as 0x0000. A keyboard that
+// only sends one byte gets the low half zeroed.
+fn read_keyboard_id(r: &mut idle::Resources) -> Result<u16, AtError> {
+    send_byte_to_at_keyboard(r, 0xF2)?;
+
+    let mut id : u16 = 0;
+    for shift in [8, 0].iter() {
+        let reply = match wait_for_at_reply(r) {
+            Ok(reply) => { reply },
+            Err(AtError::ParityError) => { return Err(AtError::ParityError); },
+            Err(_) => { break; },
+        };
+
+        id = id | ((reply as u16) << *shift);
+    }
+
+    Ok(id)
+}
+
+// Scan set 2, default typematic rate, all keys typematic/make/break, and keep scanning.
+// Unlike a reset, no self-test. A keyboard still in the middle of sending something
+// wouldn't hear it, so wait for the bus first.
+fn set_defaults(r: &mut idle::Resources) -> Result<(), AtError> {
+    wait_for_at_bus_idle(r)?;
+    send_byte_to_at_keyboard(r, 0xF6)
+}
+
+fn select_scan_set(r: &mut idle::Resources, set : u8) -> Result<(), AtError> {
+    send_byte_to_at_keyboard(r, 0xF0)?;
+    send_byte_to_at_keyboard(r, set)
+}
+
+// Set 3 only. The other two "all keys" modes (0xF7 typematic, 0xF9 make) stop break codes
+// being sent, which the FSM can't work without, so they aren't offered.
+#[cfg(not(feature = "set3-make-break"))]
+fn set_all_keys_mode(r: &mut idle::Resources) -> Result<(), AtError> {
+    set_all_typematic_make_break(r)
+}
+
+#[cfg(feature = "set3-make-break")]
+fn set_all_keys_mode(r: &mut idle::Resources) -> Result<(), AtError> {
+    set_all_make_break(r)
+}
+
+// Every key repeats while held and sends a break code when released, like set 2.
+#[cfg(not(feature = "set3-make-break"))]
+fn set_all_typematic_make_break(r: &mut idle::Resources) -> Result<(), AtError> {
+    send_byte_to_at_keyboard(r, 0xFA)
+}
+
+// Every key sends one make code and a break code; nothing repeats.
+#[cfg(feature = "set3-make-break")]
+fn set_all_make_break(r: &mut idle::Resources) -> Result<(), AtError> {
+    send_byte_to_at_keyboard(r, 0xF8)
+}
+
+// Queued; Ok only means the rate was valid, not that the keyboard took it.
+fn set_typematic(r: &mut idle::Resources, rate : u8) -> Result<(), AtError> {
+    if rate & 0x80 != 0 {
+        return Err(AtError::InvalidArgument);
+    }
+
+    queue_at_command(r, AtCommand::new(0xF3, Some(rate)));
+    Ok(())
+}
+
+// Ok(true) if the keyboard echoed 0xEE back. Ok(false) means it's talking, but answered
+// with something else (typically an ACK from a keyboard that doesn't implement echo).
+#[cfg(feature = "diagnostics")]
+fn diagnostic_echo(r: &mut idle::Resources) -> Result<bool, AtError> {
+    let reply = send_byte_for_reply(r, 0xEE)?;
+    Ok(reply == 0xEE)
+}
+
+// Light the LEDs in mask for about half a second, then put back whatever was lit before.
+fn flash_leds(r: &mut idle::Resources, mask : u8) -> () {
+    let _ = toggle_leds(r, mask);
+    delay_with_pet(r, 500_000);
+    let _ = toggle_leds(r, LED_STATE.mask());
+}
+
+// Wait out POWERON_SETTLE_MS in 20ms pieces. The XT sense interrupt stays on throughout,
+// so a host reset is still latched in RESET_REQUESTED; one that arrives cuts the wait
+// short, since the keyboard is about to be reset anyway and the host wants its answer.
+#[cfg(feature = "poweron-settle")]
+fn poweron_settle(r: &mut idle::Resources) -> () {
+    for _ in 0..(POWERON_SETTLE_MS / 20) {
+        if RESET_REQUESTED.load() {
+            return;
+        }
+        delay_with_pet(r, 20_000);
+    }
+}
+
+// Two short blinks on the status pin (the heartbeat pin; the LaunchPad's green LED), then
+// about a second dark: "plug in a keyboard". Repeats for as long as the reset loop keeps
+// finding nothing. The pin is only ours with the heartbeat feature on; see below.
+#[cfg(feature = "heartbeat")]
+fn blink_no_keyboard(r: &mut idle::Resources) -> () {
+    for _ in 0..2 {
+        rtfm::atomic(|cs| {
+            let pins = r.KEYBOARD_PINS.borrow(cs);
+            let port = r.PORT_1_2.borrow(cs);
+            pins.heartbeat.set(port);
+            pins.heartbeat.mk_out(port);
+        });
+        delay_with_pet(r, 150_000);
+
+        rtfm::atomic(|cs| {
+            r.KEYBOARD_PINS.borrow(cs)
+                .heartbeat.unset(r.PORT_1_2.borrow(cs));
+        });
+        delay_with_pet(r, 150_000);
+    }
+
+    delay_with_pet(r, 700_000);
+}
 
-    HOST_MODE.store(false);
+// Without the heartbeat feature the spare pin may be wired to something else, so leave it
+// alone; the retries keep the same pace.
+#[cfg(not(feature = "heartbeat"))]
+fn blink_no_keyboard(r: &mut idle::Resources) -> () {
+    delay_with_pet(r, 1_300_000);
 }
 
-fn toggle_leds(r: &mut idle::Resources, mask : u8) -> () {
-    send_byte_to_at_keyboard(r, 0xED);
-    delay(r, us_to_ticks!(3000));
-    send_byte_to_at_keyboard(r, mask);
+// Num Lock flashes FIRMWARE_VERSION times.
+#[cfg(feature = "version-blink")]
+fn blink_version(r: &mut idle::Resources) -> () {
+    for _ in 0..FIRMWARE_VERSION {
+        flash_leds(r, leds::LED_NUM);
+        delay_with_pet(r, 500_000);
+    }
+}
+
+// Blink out the trace, oldest entry first, as three octal digits per entry on the
+// Scroll (bit 0), Num (bit 1) and Caps (bit 2) LEDs: the Cmd tag is the top four bits of
+// the nine, the ProcReply tag the low five. A digit of zero is a dark slot.
+// LAST_BAD_FRAME follows as four more digits, then STATS if kept (see dump_stats). Takes well
+// over half a minute, so it's only ever run when asked for, with the TRACE_CHORD keys.
+#[cfg(feature = "trace")]
+fn dump_trace(r: &mut idle::Resources, trace : &Trace) -> () {
+    for i in 0..TRACE_LEN {
+        let entry : u16 = trace.entry(i);
+        for shift in [6, 3, 0].iter() {
+            flash_leds(r, ((entry >> *shift) & 0x07) as u8);
+            delay_with_pet(r, 500_000);
+        }
+        // Longer gap between entries.
+        delay_with_pet(r, 1_000_000);
+    }
+
+    let frame : u16 = LAST_BAD_FRAME.load();
+    for shift in [9, 6, 3, 0].iter() {
+        flash_leds(r, ((frame >> *shift) & 0x07) as u8);
+        delay_with_pet(r, 500_000);
+    }
+    delay_with_pet(r, 1_000_000);
+
+    #[cfg(feature = "stats")]
+    dump_stats(r);
+}
+
+// Same digits as dump_trace, one counter after another with the longer gap in between:
+// frames (six digits), then parity errors, resends, dropped frames, host resets and ACK
+// timeouts (three each). Also sent over the debug UART, if there is one.
+#[cfg(feature = "stats")]
+fn dump_stats(r: &mut idle::Resources) -> () {
+    let frames : u16 = STATS.frames.load();
+    let counts : [(&[u8], u8); 5] = [
+        (b"PARITY", STATS.parity_errors.load()),
+        (b"RESENDS", STATS.resends.load()),
+        (b"DROPPED", rtfm::atomic(|cs| { r.IN_BUFFER.borrow(cs).dropped() })),
+        (b"HOST RESETS", STATS.host_resets.load()),
+        (b"ACK TIMEOUTS", STATS.ack_timeouts.load()),
+    ];
+
+    debug_log(r, b"FRAMES HI", Some((frames >> 8) as u8));
+    debug_log(r, b"FRAMES LO", Some(frames as u8));
+    for shift in [15, 12, 9, 6, 3, 0].iter() {
+        flash_leds(r, ((frames >> *shift) & 0x07) as u8);
+        delay_with_pet(r, 500_000);
+    }
+    delay_with_pet(r, 1_000_000);
+
+    for &(msg, count) in counts.iter() {
+        debug_log(r, msg, Some(count));
+        for shift in [6, 3, 0].iter() {
+            flash_leds(r, (count >> *shift) & 0x07);
+            delay_with_pet(r, 500_000);
+        }
+        delay_with_pet(r, 1_000_000);
+    }
+}
+
+// Delay of any length in microseconds. delay() alone can't count past about 40ms (or 650ms
+// with the timer), and the watchdog wouldn't wait that long anyway, so this goes in 20ms
+// pieces and pets it in between.
+fn delay_with_pet(r: &mut idle::Resources, us : u32) -> () {
+    let mut us_left : u32 = us;
+
+    while us_left > 20000 {
+        pet_watchdog(r);
+        delay(r, us_to_ticks!(20000));
+        us_left = us_left - 20000;
+    }
+
+    pet_watchdog(r);
+    if us_left > 0 {
+        delay(r, us_to_ticks!(us_left));
+    }
 }
 
 #[cfg(not(feature = "use-timer"))]
 fn delay(r: &mut idle::Resources, n : u16) {
     let _ = r;
+    cpu_delay_cycles(n);
+}
+
+// Spin for n passes of a count-down loop; n == 0 means 65536, as with the dec/jne pair
+// itself. us_to_ticks! takes one pass as one MCLK cycle, which the calibrate feature
+// checks. Touches nothing but a register, so it's fine anywhere; only interrupts can make
+// it run long.
+#[cfg(all(any(not(feature = "use-timer"), feature = "calibrate", feature = "boot-selfcheck"),
+          not(feature = "portable-delay")))]
+fn cpu_delay_cycles(n : u16) -> () {
     unsafe {
         asm!(r#"
 1:
@@ -367,6 +1799,103 @@ fn delay(r: &mut idle::Resources, n : u16) {
     }
 }
 
+// Same contract without asm!, for when the compiler won't take the one above. The
+// volatile read keeps the loop from being folded away, but it costs a memory access per
+// pass, so expect fewer passes per microsecond; build with calibrate to see how many.
+#[cfg(all(any(not(feature = "use-timer"), feature = "calibrate", feature = "boot-selfcheck"),
+          feature = "portable-delay"))]
+fn cpu_delay_cycles(n : u16) -> () {
+    let mut left : u16 = n;
+    loop {
+        left = unsafe { core::ptr::read_volatile(&left) }.wrapping_sub(1);
+        if left == 0 {
+            break;
+        }
+    }
+}
+
+// Time the busy loop the non-timer build uses against TIMER_A2, in iterations per 10us.
+// Both run off the DCO, so this checks the loop against the clock, not the clock itself;
+// a DCO that's off frequency moves both alike. Interrupts in the middle would read as a
+// slow loop, so it runs at startup, before there are keys to take.
+#[cfg(any(feature = "calibrate", feature = "boot-selfcheck"))]
+fn measure_delay_loop(r: &mut idle::Resources) -> u32 {
+    let loops : u32 = (CALIBRATE_US * (CLOCK_CONFIG.expected_hz / 100_000)) / 10;
+
+    rtfm::atomic(|cs| {
+        let timer = r.TIMER_A2.borrow(cs);
+        // Free-running, with CCIE off so timer0_handler doesn't stop it.
+        timer.tacctl0.write(|w| unsafe { w.bits(0x0000) });
+        timer.tar.write(|w| unsafe { w.bits(0x0000) });
+        timer.taccr0.write(|w| unsafe { w.bits(0xFFFF) });
+    });
+
+    cpu_delay_cycles(loops as u16);
+
+    let ticks : u32 = rtfm::atomic(|cs| {
+        r.TIMER_A2.borrow(cs).tar.read().bits() as u32
+    });
+
+    stop_timer(r);
+    rtfm::atomic(|cs| {
+        r.TIMER_A2.borrow(cs).tacctl0.write(|w| w.ccie().set_bit());
+    });
+
+    // loops / (ticks / TIMER_HZ) is iterations per second.
+    if ticks == 0 {
+        0
+    } else {
+        (loops * (TIMER_HZ / 100_000)) / ticks
+    }
+}
+
+// Send measure_delay_loop() over the debug UART: "CAL" is what was measured, "EXP" what
+// the non-timer us_to_ticks! assumes (CLOCK_CONFIG.expected_hz / 100_000).
+#[cfg(feature = "calibrate")]
+fn report_delay_calibration(r: &mut idle::Resources) -> () {
+    let per_10us : u32 = measure_delay_loop(r);
+
+    debug_log(r, b"CAL", Some(per_10us as u8));
+    debug_log(r, b"EXP", Some((CLOCK_CONFIG.expected_hz / 100_000) as u8));
+}
+
+// boot-selfcheck: refuse to run with a busy loop more than DELAY_TOLERANCE_PCT away from
+// what us_to_ticks! assumes. A CLOCK_CONFIG that doesn't match the loop (or a timer
+// divider that doesn't match TIMER_HZ) gets every AT and XT timing wrong by the same
+// factor, which shows up as a keyboard that mostly works. This makes it a board that
+// plainly doesn't: three short blinks on the status pin, forever, with the lines idle.
+#[cfg(feature = "boot-selfcheck")]
+fn delay_self_check(r: &mut idle::Resources) -> () {
+    let measured : u32 = measure_delay_loop(r);
+    let expected : u32 = CLOCK_CONFIG.expected_hz / 100_000;
+
+    if measured * 100 >= expected * (100 - DELAY_TOLERANCE_PCT) &&
+        measured * 100 <= expected * (100 + DELAY_TOLERANCE_PCT) {
+        return;
+    }
+
+    debug_log(r, b"SELFCHECK", Some(measured as u8));
+    loop {
+        for _ in 0..3 {
+            rtfm::atomic(|cs| {
+                let pins = r.KEYBOARD_PINS.borrow(cs);
+                let port = r.PORT_1_2.borrow(cs);
+                pins.heartbeat.set(port);
+                pins.heartbeat.mk_out(port);
+            });
+            delay_with_pet(r, 100_000);
+
+            rtfm::atomic(|cs| {
+                r.KEYBOARD_PINS.borrow(cs)
+                    .heartbeat.unset(r.PORT_1_2.borrow(cs));
+            });
+            delay_with_pet(r, 100_000);
+        }
+
+        delay_with_pet(r, 1_000_000);
+    }
+}
+
 #[cfg(feature = "use-timer")]
 fn delay(r: &mut idle::Resources, time : u16) {
     start_timer(r, time);
@@ -383,3 +1912,247 @@ fn start_timer(r: &mut idle::Resources, time : u16) -> () {
         timer.taccr0.write(|w| unsafe { w.bits(time) });
     })
 }
+
+// Stop a timer that may not have expired yet, so the next start_timer counts from zero.
+#[cfg(feature = "use-timer")]
+fn stop_timer(r: &mut idle::Resources) -> () {
+    rtfm::atomic(|cs| {
+        let timer = r.TIMER_A2.borrow(cs);
+        advance_key_clock(timer.tar.read().bits());
+        timer.taccr0.write(|w| unsafe { w.bits(0x0000) });
+        timer.tar.write(|w| unsafe { w.bits(0x0000) });
+    })
+}
+
+// Count ticks of timer time toward KEY_CLOCK. Only from timer0_handler, or with interrupts
+// off (stop_timer).
+#[cfg(feature = "use-timer")]
+fn advance_key_clock(ticks : u16) -> () {
+    let spent : u32 = KEY_CLOCK_SPENT.load() as u32 + ticks as u32;
+    let whole : u8 = (spent / KEY_CLOCK_TICK as u32) as u8;
+    KEY_CLOCK.store(KEY_CLOCK.load().wrapping_add(whole));
+    KEY_CLOCK_SPENT.store((spent % KEY_CLOCK_TICK as u32) as u16);
+}
+
+// Timed XT send. Instead of a fresh delay() per half bit (which restarts the timer and
+// adds the ISR's latency every time), TIMER_A2 is left free-running in up mode with its
+// interrupt off, and every clock edge waits for the next CCR0 compare. Edges land on
+// exact multiples of the period no matter how long the code in between took, as long as
+// it took less than a period.
+#[cfg(feature = "use-timer")]
+fn start_xt_bit_clock(r: &mut idle::Resources, period : u16) -> () {
+    rtfm::atomic(|cs| {
+        let timer = r.TIMER_A2.borrow(cs);
+        // CCIE off: timer0_handler would stop the timer on the first compare.
+        timer.tacctl0.write(|w| unsafe { w.bits(0x0000) });
+        timer.tar.write(|w| unsafe { w.bits(0x0000) });
+        timer.taccr0.write(|w| unsafe { w.bits(period) });
+    })
+}
+
+// Only safe to call right after a compare event, while TAR is still below the new period;
+// otherwise the timer counts all the way to 0xFFFF before wrapping.
+#[cfg(feature = "use-timer")]
+fn set_xt_bit_clock(r: &mut idle::Resources, period : u16) -> () {
+    rtfm::atomic(|cs| {
+        r.TIMER_A2.borrow(cs).taccr0.write(|w| unsafe { w.bits(period) });
+    })
+}
+
+#[cfg(feature = "use-timer")]
+fn wait_xt_bit_clock(r: &mut idle::Resources) -> () {
+    while !rtfm::atomic(|cs| {
+        r.TIMER_A2.borrow(cs).tacctl0.read().ccifg().bit_is_set()
+    }) {
+
+    }
+
+    rtfm::atomic(|cs| {
+        r.TIMER_A2.borrow(cs).tacctl0.modify(|_, w| w.ccifg().clear_bit());
+    })
+}
+
+// Back to the one-shot mode delay() and wait_while() expect.
+#[cfg(feature = "use-timer")]
+fn stop_xt_bit_clock(r: &mut idle::Resources) -> () {
+    stop_timer(r);
+    rtfm::atomic(|cs| {
+        r.TIMER_A2.borrow(cs).tacctl0.write(|w| w.ccie().set_bit());
+    })
+}
+
+// Same framing as send_xt_bit, but the clock is low for one period and high for the next.
+#[cfg(feature = "use-timer")]
+fn send_xt_bit_timed(r: &mut idle::Resources, bit : u8) -> () {
+    rtfm::atomic(|cs| {
+        let pins = r.KEYBOARD_PINS.borrow(cs);
+        let port = r.PORT_1_2.borrow(cs);
+        pins.xt_data_write(port, bit == 1);
+
+        pins.xt_clk.unset(port);
+    });
+
+    wait_xt_bit_clock(r);
+
+    rtfm::atomic(|cs| {
+        r.KEYBOARD_PINS.borrow(cs)
+            .xt_clk.set(r.PORT_1_2.borrow(cs));
+    });
+
+    wait_xt_bit_clock(r);
+}
+
+// One line on the debug UART: msg, then the value in hex if there is one. Slow (about 1ms
+// a character), so only ever called from the idle context.
+#[cfg(feature = "debug-uart")]
+fn debug_log(r: &mut idle::Resources, msg : &[u8], val : Option<u8>) -> () {
+    for c in msg {
+        debug_tx_byte(r, *c);
+    }
+
+    if let Some(v) = val {
+        let hex : &[u8; 16] = b"0123456789ABCDEF";
+        for c in [b' ', b'0', b'x', hex[(v >> 4) as usize], hex[(v & 0x0F) as usize]].iter() {
+            debug_tx_byte(r, *c);
+        }
+    }
+
+    debug_tx_byte(r, b'\r');
+    debug_tx_byte(r, b'\n');
+}
+
+#[cfg(not(feature = "debug-uart"))]
+fn debug_log(r: &mut idle::Resources, msg : &[u8], val : Option<u8>) -> () {
+    let _ = (r, msg, val);
+}
+
+// diag-as-keys: type msg to the PC, for when there's no serial adapter to hand; open a
+// text editor and watch. Queued behind any real keys, as make/break pairs.
+#[cfg(feature = "diag-as-keys")]
+fn diag_keys(r: &mut idle::Resources, msg : &[u8]) -> () {
+    let left : u8 = DIAG_KEYS_LEFT.load();
+    if left == 0 {
+        return;
+    }
+    DIAG_KEYS_LEFT.store(left - 1);
+
+    for c in msg {
+        if let Some(key) = scancode::xt_key_for_ascii(*c) {
+            queue_byte_to_pc(r, key);
+            queue_byte_to_pc(r, key | 0x80);
+        }
+    }
+}
+
+#[cfg(feature = "diag-as-keys")]
+fn diag_keys_good_frame() -> () {
+    let good : u8 = DIAG_KEYS_GOOD.load() + 1;
+    if good >= DIAG_KEYS_QUIET {
+        DIAG_KEYS_GOOD.store(0);
+        DIAG_KEYS_LEFT.store(DIAG_KEYS_BURST);
+    } else {
+        DIAG_KEYS_GOOD.store(good);
+    }
+}
+
+#[cfg(not(feature = "diag-as-keys"))]
+fn diag_keys(r: &mut idle::Resources, msg : &[u8]) -> () {
+    let _ = (r, msg);
+}
+
+#[cfg(not(feature = "diag-as-keys"))]
+fn diag_keys_good_frame() -> () {
+
+}
+
+// Same idea as send_xt_bit: set the line, then wait out the bit. Start bit, 8 data bits
+// LSb first, stop bit.
+#[cfg(feature = "debug-uart")]
+fn debug_tx_byte(r: &mut idle::Resources, byte : u8) -> () {
+    // Low start bit in bit 0, high stop bit in bit 9.
+    let mut frame : u16 = ((byte as u16) << 1) | (1 << 9);
+
+    // A whole line takes tens of milliseconds at 4800 baud; one byte, about 2.
+    pet_watchdog(r);
+    for _ in 0..10 {
+        rtfm::atomic(|cs| {
+            let pins = r.KEYBOARD_PINS.borrow(cs);
+            let port = r.PORT_1_2.borrow(cs);
+            if frame & 0x01 == 1 {
+                pins.debug_tx.set(port);
+            } else {
+                pins.debug_tx.unset(port);
+            }
+        });
+
+        delay(r, us_to_ticks!(DEBUG_UART_BIT_US));
+        frame = frame >> 1;
+    }
+}
+
+// Restart the watchdog count. Called from every loop that can legitimately run for longer
+// than the watchdog interval, so only a loop that never ends resets the micro.
+#[cfg(feature = "watchdog")]
+fn pet_watchdog(r: &mut idle::Resources) -> () {
+    rtfm::atomic(|cs| {
+        r.WATCHDOG_TIMER.borrow(cs).wdtctl.write(|w| unsafe {
+            w.bits(WDT_PASSWORD).wdtcntcl().set_bit()
+        });
+    })
+}
+
+#[cfg(not(feature = "watchdog"))]
+fn pet_watchdog(r: &mut idle::Resources) -> () {
+    let _ = r;
+}
+
+// Flip the heartbeat pin: once per trip through the FSM, and once per pass while waiting
+// for a key. A steady square wave means the idle loop is waiting normally; a pin stuck
+// high or low means it's stuck somewhere else. One read-modify-write, so it costs next to
+// nothing.
+#[cfg(feature = "heartbeat")]
+fn heartbeat(r: &mut idle::Resources) -> () {
+    rtfm::atomic(|cs| {
+        r.KEYBOARD_PINS.borrow(cs)
+            .heartbeat.toggle(r.PORT_1_2.borrow(cs));
+    })
+}
+
+#[cfg(not(feature = "heartbeat"))]
+fn heartbeat(r: &mut idle::Resources) -> () {
+    let _ = r;
+}
+
+// Spin while busy() holds, giving up after approximately time ticks.
+#[cfg(feature = "use-timer")]
+fn wait_while<F>(r: &mut idle::Resources, time : u16, mut busy : F) -> Result<(), AtError>
+    where F: FnMut(&mut idle::Resources) -> bool {
+    start_timer(r, time);
+    while busy(r) {
+        pet_watchdog(r);
+        if TIMEOUT.load() {
+            return Err(AtError::ClockTimeout);
+        }
+    }
+    stop_timer(r);
+    Ok(())
+}
+
+#[cfg(not(feature = "use-timer"))]
+fn wait_while<F>(r: &mut idle::Resources, time : u16, mut busy : F) -> Result<(), AtError>
+    where F: FnMut(&mut idle::Resources) -> bool {
+    // Counting passes alone would take each one as a single tick, when busy() (a critical
+    // section and a pin read) and the watchdog pet cost dozens of cycles. Burning a fixed
+    // WAIT_POLL_TICKS per pass makes that cost the small part, so the timeout runs long by
+    // a fraction rather than a multiple.
+    let mut ticks_left : u16 = time;
+    while busy(r) {
+        pet_watchdog(r);
+        if ticks_left == 0 {
+            return Err(AtError::ClockTimeout);
+        }
+        cpu_delay_cycles(WAIT_POLL_TICKS);
+        ticks_left = ticks_left.saturating_sub(WAIT_POLL_TICKS);
+    }
+    Ok(())
+}
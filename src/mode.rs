@@ -0,0 +1,21 @@
+use msp430_atomic::AtomicBool;
+
+// Whether the PORT1 interrupt is shifting a byte out to the keyboard (host mode) or in
+// from it (device mode, the default).
+//
+// Only the idle context changes the mode, and only while nothing is being clocked: it
+// enters host mode after the request-to-send, once KEY_OUT is loaded, and leaves it once the
+// keyboard ACKs or the transfer times out. The PORT1 interrupt only reads it.
+static HOST_MODE : AtomicBool = AtomicBool::new(false);
+
+pub fn is_host_mode() -> bool {
+    HOST_MODE.load()
+}
+
+pub fn enter_host_mode() -> () {
+    HOST_MODE.store(true);
+}
+
+pub fn exit_host_mode() -> () {
+    HOST_MODE.store(false);
+}
@@ -0,0 +1,328 @@
+// Scan code translation tables. Each table is indexed by the code the AT keyboard sends
+// and yields the XT (set 1) make code. Zero means "no equivalent"; such keys are dropped.
+// E0_SET2_TO_SET1 is mostly empty, so it's a list of (AT, XT) pairs instead.
+
+pub const SET2_TO_SET1 : [u8; 0x85] =
+    // 0    1    2    3    4    5    6    7    8    9    A    B    C    D    E    F
+    [0x00,0x43,0x00,0x3F,0x3D,0x3B,0x3C,0x58,0x00,0x44,0x42,0x40,0x3E,0x0F,0x29,0x00,
+    0x00,0x38,0x2A,0x00,0x1D,0x10,0x02,0x00,0x00,0x00,0x2C,0x1F,0x1E,0x11,0x03,0x00,
+    0x00,0x2E,0x2D,0x20,0x12,0x05,0x04,0x00,0x00,0x39,0x2F,0x21,0x14,0x13,0x06,0x00,
+    0x00,0x31,0x30,0x23,0x22,0x15,0x07,0x00,0x00,0x00,0x32,0x24,0x16,0x08,0x09,0x00,
+    0x00,0x33,0x25,0x17,0x18,0x0B,0x0A,0x00,0x00,0x34,0x35,0x26,0x27,0x19,0x0C,0x00,
+    0x00,0x00,0x28,0x00,0x1A,0x0D,0x00,0x00,0x3A,0x36,0x1C,0x1B,0x00,0x2B,0x00,0x00,
+    0x00,0x00,0x00,0x00,0x00,0x00,0x0E,0x00,0x00,0x4F,0x00,0x4B,0x47,0x00,0x00,0x00,
+    0x52,0x53,0x50,0x4C,0x4D,0x48,0x01,0x45,0x57,0x4E,0x51,0x4A,0x37,0x49,0x46,0x00,
+    0x00,0x00,0x00,0x41,0x54];
+
+// Second byte of E0-prefixed keys. The E0 itself is forwarded to the XT unchanged. The
+// Windows and Menu keys (E0 1F, 27, 2F) come out as E0 5B, 5C, 5D, and the ACPI Power,
+// Sleep and Wake keys (E0 37, 3F, 5E) as E0 5E, 5F, 63, as on an enhanced keyboard in
+// set 1; an XT BIOS just ignores them. Anything not listed has no equivalent.
+pub const E0_SET2_TO_SET1 : &'static [(u8, u8)] = &[
+    (0x11, 0x38), // Right Alt
+    (0x12, 0x2A), // Fake Left Shift
+    (0x14, 0x1D), // Right Ctrl
+    (0x1F, 0x5B), // Left Windows
+    (0x27, 0x5C), // Right Windows
+    (0x2F, 0x5D), // Menu
+    (0x37, 0x5E), // Power
+    (0x3F, 0x5F), // Sleep
+    (0x4A, 0x35), // Keypad /
+    (0x59, 0x36), // Fake Right Shift
+    (0x5A, 0x1C), // Keypad Enter
+    (0x5E, 0x63), // Wake
+    (0x69, 0x4F), // End
+    (0x6B, 0x4B), // Left
+    (0x6C, 0x47), // Home
+    (0x70, 0x52), // Insert
+    (0x71, 0x53), // Delete
+    (0x72, 0x50), // Down
+    (0x74, 0x4D), // Right
+    (0x75, 0x48), // Up
+    (0x7A, 0x51), // Page Down
+    (0x7C, 0x37), // Print Screen
+    (0x7D, 0x49), // Page Up
+    (0x7E, 0x46), // Ctrl+Break
+];
+
+// Set 3 has one code per key and no prefixes other than F0 for break. Keys that only
+// exist on enhanced keyboards (cursor block, right Ctrl/Alt, keypad / and Enter) are sent
+// as the 83-key XT key that does the same job, since set 3 gives us no E0 to go with them.
+pub const SET3_TO_SET1 : [u8; 0x85] =
+    // 0    1    2    3    4    5    6    7    8    9    A    B    C    D    E    F
+    [0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x3B,0x01,0x00,0x00,0x00,0x00,0x0F,0x29,0x3C,
+    0x00,0x1D,0x2A,0x00,0x3A,0x10,0x02,0x3D,0x00,0x38,0x2C,0x1F,0x1E,0x11,0x03,0x3E,
+    0x00,0x2E,0x2D,0x20,0x12,0x05,0x04,0x3F,0x00,0x39,0x2F,0x21,0x14,0x13,0x06,0x40,
+    0x00,0x31,0x30,0x23,0x22,0x15,0x07,0x41,0x00,0x38,0x32,0x24,0x16,0x08,0x09,0x42,
+    0x00,0x33,0x25,0x17,0x18,0x0B,0x0A,0x43,0x00,0x34,0x35,0x26,0x27,0x19,0x0C,0x44,
+    0x00,0x00,0x28,0x00,0x1A,0x0D,0x57,0x37,0x1D,0x36,0x1C,0x1B,0x2B,0x00,0x58,0x46,
+    0x50,0x4B,0x00,0x48,0x53,0x4F,0x0E,0x52,0x00,0x4F,0x4D,0x4B,0x47,0x51,0x47,0x49,
+    0x52,0x53,0x50,0x4C,0x4D,0x48,0x45,0x35,0x00,0x1C,0x51,0x00,0x4E,0x49,0x37,0x00,
+    0x00,0x00,0x00,0x00,0x4A];
+
+// Spot checks of well-known keys, so a hand edit that shifts a row fails to build rather
+// than producing a keyboard with a few wrong keys. Duplicates and the shape of each table
+// are checked by the unit tests at the bottom, which can loop.
+macro_rules! assert_maps {
+    ($name:ident, $table:ident, $at:expr, $xt:expr) => {
+        #[allow(dead_code)]
+        const $name : [(); 0 - (($table[$at] != $xt) as usize)] = [];
+    }
+}
+
+assert_maps!(_SET2_ESC, SET2_TO_SET1, 0x76, 0x01);
+assert_maps!(_SET2_1, SET2_TO_SET1, 0x16, 0x02);
+assert_maps!(_SET2_0, SET2_TO_SET1, 0x45, 0x0B);
+assert_maps!(_SET2_BKSP, SET2_TO_SET1, 0x66, 0x0E);
+assert_maps!(_SET2_TAB, SET2_TO_SET1, 0x0D, 0x0F);
+assert_maps!(_SET2_A, SET2_TO_SET1, 0x1C, 0x1E);
+assert_maps!(_SET2_B, SET2_TO_SET1, 0x32, 0x30);
+assert_maps!(_SET2_C, SET2_TO_SET1, 0x21, 0x2E);
+assert_maps!(_SET2_D, SET2_TO_SET1, 0x23, 0x20);
+assert_maps!(_SET2_E, SET2_TO_SET1, 0x24, 0x12);
+assert_maps!(_SET2_F, SET2_TO_SET1, 0x2B, 0x21);
+assert_maps!(_SET2_ENTER, SET2_TO_SET1, 0x5A, 0x1C);
+assert_maps!(_SET2_SPACE, SET2_TO_SET1, 0x29, 0x39);
+assert_maps!(_SET2_CAPS, SET2_TO_SET1, 0x58, 0x3A);
+assert_maps!(_SET2_LSHIFT, SET2_TO_SET1, 0x12, 0x2A);
+assert_maps!(_SET2_RSHIFT, SET2_TO_SET1, 0x59, 0x36);
+assert_maps!(_SET2_CTRL, SET2_TO_SET1, 0x14, 0x1D);
+assert_maps!(_SET2_ALT, SET2_TO_SET1, 0x11, 0x38);
+assert_maps!(_SET2_F1, SET2_TO_SET1, 0x05, 0x3B);
+assert_maps!(_SET2_F7, SET2_TO_SET1, 0x83, 0x41);
+assert_maps!(_SET2_F10, SET2_TO_SET1, 0x09, 0x44);
+assert_maps!(_SET2_F12, SET2_TO_SET1, 0x07, 0x58);
+assert_maps!(_SET2_NUM, SET2_TO_SET1, 0x77, 0x45);
+assert_maps!(_SET2_SCROLL, SET2_TO_SET1, 0x7E, 0x46);
+assert_maps!(_SET2_KP0, SET2_TO_SET1, 0x70, 0x52);
+assert_maps!(_SET2_KPSTAR, SET2_TO_SET1, 0x7C, 0x37);
+assert_maps!(_SET2_SYSRQ, SET2_TO_SET1, 0x84, 0x54);
+
+assert_maps!(_SET3_ESC, SET3_TO_SET1, 0x08, 0x01);
+assert_maps!(_SET3_A, SET3_TO_SET1, 0x1C, 0x1E);
+assert_maps!(_SET3_ENTER, SET3_TO_SET1, 0x5A, 0x1C);
+assert_maps!(_SET3_CAPS, SET3_TO_SET1, 0x14, 0x3A);
+assert_maps!(_SET3_CTRL, SET3_TO_SET1, 0x11, 0x1D);
+assert_maps!(_SET3_ALT, SET3_TO_SET1, 0x19, 0x38);
+assert_maps!(_SET3_F1, SET3_TO_SET1, 0x07, 0x3B);
+assert_maps!(_SET3_NUM, SET3_TO_SET1, 0x76, 0x45);
+assert_maps!(_SET3_SCROLL, SET3_TO_SET1, 0x5F, 0x46);
+
+// Build-time key remapping, applied to the XT code after translation: (from, to), first
+// match wins. Since make and break codes both go through translate(), a remapped key
+// also releases as what it was remapped to. E0-prefixed keys are remapped by their
+// second byte and keep the prefix. Common entries:
+//   (0x3a, 0x1d)                  Caps Lock acts as Ctrl (and no longer toggles its LED;
+//                                 see keyfsm::LOCK_KEYS)
+//   (0x3a, 0x1d), (0x1d, 0x3a)    Caps Lock and Ctrl swapped (both Ctrls, as E0 1D is
+//                                 right Ctrl)
+//   (0x01, 0x29), (0x29, 0x01)    Esc and ` swapped
+// Codes are the set 1 make codes, as in the tables above.
+pub const REMAP : &'static [(u8, u8)] = &[];
+
+// XT make codes for typing 0-9 and A-F.
+pub const XT_HEX_DIGITS : [u8; 16] =
+    [0x0B,0x02,0x03,0x04,0x05,0x06,0x07,0x08,0x09,0x0A,0x1E,0x30,0x2E,0x20,0x12,0x21];
+
+// XT make codes for typing a-z, unshifted.
+#[cfg(feature = "diag-as-keys")]
+pub const XT_LETTERS : [u8; 26] =
+    [0x1E,0x30,0x2E,0x20,0x12,0x21,0x22,0x23,0x17,0x24,0x25,0x26,0x32,
+     0x31,0x18,0x19,0x10,0x13,0x1F,0x14,0x16,0x2F,0x11,0x2D,0x15,0x2C];
+
+#[cfg(feature = "diag-as-keys")]
+assert_maps!(_LETTER_A, XT_LETTERS, 0, 0x1E);
+#[cfg(feature = "diag-as-keys")]
+assert_maps!(_LETTER_M, XT_LETTERS, 12, 0x32);
+#[cfg(feature = "diag-as-keys")]
+assert_maps!(_LETTER_Z, XT_LETTERS, 25, 0x2C);
+
+// The XT make code that types c: lowercase letters, digits, space and newline (Enter).
+// Anything else has no key here.
+#[cfg(feature = "diag-as-keys")]
+pub fn xt_key_for_ascii(c : u8) -> Option<u8> {
+    if c >= b'a' && c <= b'z' {
+        Some(XT_LETTERS[(c - b'a') as usize])
+    } else if c >= b'0' && c <= b'9' {
+        Some(XT_HEX_DIGITS[(c - b'0') as usize])
+    } else if c == b' ' {
+        Some(0x39)
+    } else if c == b'\n' {
+        Some(0x1C)
+    } else {
+        None
+    }
+}
+
+// Only set 2 and set 3 are ever selected; anything else is treated as set 2.
+pub fn translate(set : u8, at_code : u8) -> Option<u8> {
+    let xt_code = match set {
+        3 => { lookup(&SET3_TO_SET1, at_code) },
+        _ => { lookup(&SET2_TO_SET1, at_code) },
+    };
+
+    xt_code.map(remap)
+}
+
+#[cfg(not(feature = "xt-strict"))]
+pub fn translate_e0(set2 : u8) -> Option<u8> {
+    lookup_pairs(E0_SET2_TO_SET1, set2).map(remap)
+}
+
+// xt-strict: the E0 is never sent, so the second byte has to stand on its own.
+#[cfg(feature = "xt-strict")]
+pub fn translate_e0(set2 : u8) -> Option<u8> {
+    lookup_pairs(E0_SET2_TO_SET1, set2).and_then(strict_fallback).map(remap)
+}
+
+// What an 83-key XT keyboard has in place of each enhanced key, for machines whose BIOS
+// predates E0. Zero drops the key. Anything not listed is sent without its E0, which for
+// most keys (right Ctrl/Alt, keypad Enter and /, the cursor block) lands on the key that
+// did the same job: the keypad's cursor keys, or the left-hand modifier.
+#[cfg(feature = "xt-strict")]
+pub const E0_FALLBACK : &'static [(u8, u8)] = &[
+    (0x2a, 0x00), // Fake shifts wrapped around enhanced keys; they'd press a real Shift.
+    (0x36, 0x00),
+    (0x37, 0x37), // Print Screen is the PrtSc/* key (Shift+PrtSc prints, as on the XT).
+    (0x46, 0x46), // Ctrl+Break is Ctrl+Scroll Lock.
+    (0x5b, 0x00), // Windows and Menu keys have no XT counterpart.
+    (0x5c, 0x00),
+    (0x5d, 0x00),
+    (0x5e, 0x00), // Nor do Power, Sleep and Wake.
+    (0x5f, 0x00),
+    (0x63, 0x00),
+];
+
+#[cfg(feature = "xt-strict")]
+fn strict_fallback(xt_code : u8) -> Option<u8> {
+    for &(from, to) in E0_FALLBACK.iter() {
+        if from == xt_code {
+            return if to == 0 { None } else { Some(to) };
+        }
+    }
+
+    Some(xt_code)
+}
+
+fn remap(xt_code : u8) -> u8 {
+    for &(from, to) in REMAP.iter() {
+        if from == xt_code {
+            return to;
+        }
+    }
+
+    xt_code
+}
+
+// Tables stop at the highest code they translate; anything past the end has no equivalent.
+fn lookup(table : &[u8], code : u8) -> Option<u8> {
+    match table.get(code as usize) {
+        None | Some(&0) => { None },
+        Some(&k) => { Some(k) },
+    }
+}
+
+fn lookup_pairs(table : &[(u8, u8)], code : u8) -> Option<u8> {
+    for &(from, to) in table.iter() {
+        if from == code {
+            return Some(to);
+        }
+    }
+
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The same spot checks as assert_maps!, for the table a const can't index.
+    #[test]
+    fn e0_spot_checks() {
+        let checks : [(u8, u8); 16] = [
+            (0x11, 0x38), (0x14, 0x1D), (0x4A, 0x35), (0x5A, 0x1C),
+            (0x75, 0x48), (0x72, 0x50), (0x6B, 0x4B), (0x74, 0x4D),
+            (0x70, 0x52), (0x71, 0x53), (0x1F, 0x5B), (0x27, 0x5C),
+            (0x2F, 0x5D), (0x37, 0x5E), (0x3F, 0x5F), (0x5E, 0x63),
+        ];
+
+        for &(at, xt) in checks.iter() {
+            assert_eq!(lookup_pairs(E0_SET2_TO_SET1, at), Some(xt));
+        }
+        assert_eq!(lookup_pairs(E0_SET2_TO_SET1, 0x1C), None);
+    }
+
+    // Set 3 keys that share their XT code with another key on purpose: the enhanced keys
+    // sent as their 83-key counterparts (see SET3_TO_SET1). Set 2 has none; its enhanced
+    // keys are all behind E0.
+    const SET3_SHARED : [u8; 15] = [
+        0x39, 0x58, // Right Alt, right Ctrl
+        0x57, 0x77, 0x79, // Print Screen, keypad / and Enter
+        0x60, 0x61, 0x63, 0x6a, // Down, Left, Up, Right
+        0x64, 0x65, 0x67, 0x6e, 0x6d, 0x6f, // Delete, End, Insert, Home, Page Down, Page Up
+    ];
+
+    // Two codes landing on the same XT key is only right if one of them is listed.
+    fn check_duplicates(table : &[u8], shared : &[u8]) -> () {
+        for (i, a) in table.iter().enumerate() {
+            for (j, b) in table.iter().enumerate().skip(i + 1) {
+                if *a != 0 && a == b {
+                    assert!(shared.contains(&(i as u8)) || shared.contains(&(j as u8)),
+                        "{:#04x} and {:#04x} both map to {:#04x}", i, j, a);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn no_unexpected_duplicates() {
+        check_duplicates(&SET2_TO_SET1, &[]);
+        check_duplicates(&SET3_TO_SET1, &SET3_SHARED);
+
+        for (i, &(at, xt)) in E0_SET2_TO_SET1.iter().enumerate() {
+            for &(at2, xt2) in E0_SET2_TO_SET1[i + 1..].iter() {
+                assert!(at != at2, "E0 {:#04x} listed twice", at);
+                assert!(xt != xt2, "E0 {:#04x} and {:#04x} both map to {:#04x}", at, at2, xt);
+            }
+        }
+    }
+
+    // Tables end at their last code, and everything in them is a make code.
+    #[test]
+    fn table_shape() {
+        for table in [&SET2_TO_SET1[..], &SET3_TO_SET1[..]].iter() {
+            assert!(table.len() <= 256);
+            assert!(table[table.len() - 1] != 0);
+            assert!(table.iter().all(|k| *k < 0x80));
+        }
+
+        assert!(E0_SET2_TO_SET1.iter().all(|&(_, xt)| xt != 0 && xt < 0x80));
+    }
+
+    // Every code a keyboard could send, through translate() as the FSM calls it.
+    #[test]
+    fn walk_every_code() {
+        for code in 0..256 {
+            let code = code as u8;
+            for &(set, table) in [(2, &SET2_TO_SET1[..]), (3, &SET3_TO_SET1[..])].iter() {
+                let expected = match table.get(code as usize) {
+                    None | Some(&0) => { None },
+                    Some(&k) => { Some(k) },
+                };
+                assert_eq!(translate(set, code), expected.map(remap));
+            }
+
+            let expected = E0_SET2_TO_SET1.iter().find(|&&(at, _)| at == code).map(|&(_, xt)| xt);
+            assert_eq!(lookup_pairs(E0_SET2_TO_SET1, code), expected);
+        }
+    }
+
+    #[test]
+    fn past_end_of_table() {
+        assert_eq!(translate(2, 0x85), None);
+        assert_eq!(translate(3, 0x85), None);
+        assert_eq!(translate(2, 0xFF), None);
+        assert_eq!(translate(3, 0xFF), None);
+    }
+}
@@ -0,0 +1,367 @@
+// sim: the translation path end to end on the host, from AT frames in to XT line
+// transitions out, for table-driven tests. The keyboard and the PC are both PortModel:
+// AT frames are clocked in one bit at a time the way the PORT1 interrupt does it, and
+// each command from the FSM is carried out the way the idle loop does it, minus timing,
+// the command queue and the OUT_BUFFER detour. Keep the two in step with main.rs.
+use core::mem;
+
+use driver::{KeyboardPins, Port, PortModel, Reg};
+use keybuffer::{DefaultBuffer, KeyIn, XtFrameBits};
+use keyfsm::{Cmd, Fsm, ProcReply};
+
+// Enough for a dozen XT bytes.
+pub const SIM_LOG_LEN : usize = 384;
+
+// The XT lines as the PC sees them: low only while we drive them low.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct XtLines {
+    pub clk : bool,
+    pub data : bool,
+}
+
+pub struct Sim {
+    port : PortModel,
+    pins : KeyboardPins,
+    key_in : KeyIn,
+    in_buffer : DefaultBuffer,
+    fsm : Fsm,
+    next_cmd : Cmd,
+    reset_requested : bool,
+    leds : u8,
+    log : [XtLines; SIM_LOG_LEN],
+    log_len : usize,
+}
+
+impl Sim {
+    pub fn new() -> Sim {
+        let mut sim = Sim {
+            port : PortModel::new(),
+            pins : KeyboardPins::new(),
+            key_in : KeyIn::new(),
+            in_buffer : DefaultBuffer::new(),
+            fsm : Fsm::start(),
+            next_cmd : Cmd::WaitForKey,
+            reset_requested : false,
+            leds : 0,
+            log : [XtLines { clk : true, data : true }; SIM_LOG_LEN],
+            log_len : 0,
+        };
+
+        sim.pins.idle(&sim.port);
+        sim.next_cmd = sim.run_fsm(&ProcReply::init());
+        sim
+    }
+
+    // A good frame carrying byte, as a keyboard sends it: start bit, data LSb first, odd
+    // parity, stop bit.
+    pub fn at_frame(&mut self, byte : u8) -> () {
+        self.queue_at_frame(byte);
+        self.settle();
+    }
+
+    // Any 11 bits, in the order they're clocked in. A frame that fails is_valid_frame() is
+    // dropped; the resend the firmware would ask for isn't modeled.
+    pub fn at_bits(&mut self, bits : &[bool; 11]) -> () {
+        self.clock_in(bits);
+        self.settle();
+    }
+
+    // A good frame that arrives while the idle loop is busy elsewhere: it waits in
+    // IN_BUFFER until something else lets the idle loop run.
+    pub fn queue_at_frame(&mut self, byte : u8) -> () {
+        let parity : u16 = if byte.count_ones() % 2 == 0 { 1 } else { 0 };
+        let mut wire : u16 = (1 << 10) | (parity << 9) | ((byte as u16) << 1);
+        let mut bits = [false; 11];
+        for b in bits.iter_mut() {
+            *b = wire & 0x01 == 1;
+            wire = wire >> 1;
+        }
+        self.clock_in(&bits);
+    }
+
+    // The PC holding the XT clock low, as the PORT1 interrupt latches it.
+    pub fn host_reset(&mut self) -> () {
+        self.reset_requested = true;
+        self.settle();
+    }
+
+    fn clock_in(&mut self, bits : &[bool; 11]) -> () {
+        let at_data : u8 = 1 << ::driver::DEFAULT_PIN_MAP.at_data;
+
+        for b in bits.iter() {
+            let pins_in : u8 = self.port.read(Reg::In);
+            self.port.pins_in.set(if *b { pins_in | at_data } else { pins_in & !at_data });
+            self.key_in.shift_in(self.pins.at_data.is_set(&self.port));
+
+            if self.key_in.is_full() {
+                let valid = self.key_in.is_valid_frame();
+                if let Some((k, _)) = self.key_in.take_raw() {
+                    if valid {
+                        let _ = self.in_buffer.put(k as u16);
+                    }
+                }
+                self.key_in.clear();
+            }
+        }
+    }
+
+    // Every change on the XT lines so far, starting from both released.
+    pub fn transitions(&self) -> &[XtLines] {
+        &self.log[..self.log_len]
+    }
+
+    // The bytes a PC would have clocked in from transitions(): it samples data on each
+    // rising clock edge, two start bits and then eight data bits LSb first.
+    pub fn xt_bytes(&self, out : &mut [u8]) -> usize {
+        let mut count : usize = 0;
+        let mut bit : u8 = 0;
+        let mut byte : u8 = 0;
+        let mut clk : bool = true;
+
+        for lines in self.transitions() {
+            if lines.clk && !clk {
+                if bit >= 2 {
+                    byte = byte | ((lines.data as u8) << (bit - 2));
+                }
+                bit = bit + 1;
+                if bit == 10 {
+                    out[count] = byte;
+                    count = count + 1;
+                    bit = 0;
+                    byte = 0;
+                }
+            }
+            clk = lines.clk;
+        }
+
+        count
+    }
+
+    pub fn leds(&self) -> u8 {
+        self.leds
+    }
+
+    // Carry out commands until the FSM waits for a key that isn't there yet.
+    fn settle(&mut self) -> () {
+        loop {
+            let cmd = mem::replace(&mut self.next_cmd, Cmd::WaitForKey);
+            let reply = match cmd {
+                // A host reset goes ahead of any keys waiting.
+                Cmd::WaitForKey if self.reset_requested => {
+                    self.reset_requested = false;
+                    ProcReply::HostResetRequested
+                },
+                Cmd::WaitForKey => {
+                    match self.in_buffer.take() {
+                        Some(k) => { ProcReply::GrabbedKey(k as u8) },
+                        None => { return; },
+                    }
+                },
+                Cmd::ClearBuffer => {
+                    self.release_held_keys();
+                    self.in_buffer.flush();
+                    ProcReply::ClearedBuffer
+                },
+                Cmd::ToggleLed(m) => {
+                    self.leds = self.leds ^ m;
+                    ProcReply::LedToggled(self.leds)
+                },
+                Cmd::SendXTKey(k) => {
+                    self.send_xt_byte(k);
+                    ProcReply::SentKey(k)
+                },
+                Cmd::SendXTSequence(seq) => {
+                    for b in seq.iter() {
+                        self.send_xt_byte(*b);
+                    }
+                    ProcReply::SentSequence
+                },
+                Cmd::Reset => {
+                    self.release_held_keys();
+                    ProcReply::DidReset
+                },
+                // There's no keyboard on the other end to ask.
+                Cmd::Identify => { ProcReply::Identified },
+                Cmd::HostReset => {
+                    self.send_xt_byte(0xaa);
+                    ProcReply::KeyboardReset
+                },
+                Cmd::SetScanSet(set) => { ProcReply::ScanSetSelected(set) },
+                #[cfg(feature = "wiring-test")]
+                Cmd::XtTestPattern => { ProcReply::SentTestPattern },
+                #[cfg(feature = "trace")]
+                Cmd::DumpTrace => { ProcReply::TraceDumped },
+                #[cfg(feature = "passthrough-unknown")]
+                Cmd::SendUnknownKey(k) => {
+                    self.send_xt_byte(k);
+                    ProcReply::SentKey(k)
+                },
+                // No clock here, so every key is as young as the buffer's; nothing goes.
+                #[cfg(feature = "use-timer")]
+                Cmd::FlushStale => { ProcReply::FlushedStale },
+            };
+
+            self.next_cmd = self.run_fsm(&reply);
+        }
+    }
+
+    fn release_held_keys(&mut self) -> () {
+        let mut held = [0; 8];
+        let mut n : usize = 0;
+        self.fsm.release_held(|k| { held[n] = k; n = n + 1; });
+        for k in held[..n].iter() {
+            self.send_xt_byte(*k);
+        }
+    }
+
+    // As the idle loop recovers from an Err.
+    fn run_fsm(&mut self, reply : &ProcReply) -> Cmd {
+        match self.fsm.run(reply) {
+            Ok(cmd) => { cmd },
+            Err(_) => {
+                self.fsm.reset();
+                self.fsm.run(&ProcReply::init()).unwrap_or(Cmd::WaitForKey)
+            },
+        }
+    }
+
+    // send_byte_to_pc, line for line, with the host never busy.
+    fn send_xt_byte(&mut self, byte : u8) -> () {
+        self.pins.xt_out(&self.port);
+        self.record();
+
+        for bit in XtFrameBits::new(byte) {
+            self.pins.xt_data_write(&self.port, bit == 1);
+            self.record();
+            self.pins.xt_clk.unset(&self.port);
+            self.record();
+            self.pins.xt_clk.set(&self.port);
+            self.record();
+        }
+
+        self.pins.xt_in(&self.port);
+        self.record();
+    }
+
+    fn record(&mut self) -> () {
+        let dir : u8 = self.port.read(Reg::Dir);
+        let out : u8 = self.port.read(Reg::Out);
+        let low = |pin : u8| { (dir & !out) & (1 << pin) != 0 };
+        let lines = XtLines {
+            clk : !low(::driver::DEFAULT_PIN_MAP.xt_clk),
+            data : !low(::driver::DEFAULT_PIN_MAP.xt_data),
+        };
+
+        let last = if self.log_len == 0 {
+            XtLines { clk : true, data : true }
+        } else {
+            self.log[self.log_len - 1]
+        };
+
+        if lines != last && self.log_len < SIM_LOG_LEN {
+            self.log[self.log_len] = lines;
+            self.log_len = self.log_len + 1;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use leds::LED_CAPS;
+
+    #[cfg(not(feature = "xt-strict"))]
+    const UP_XT : &'static [u8] = &[0xe0, 0x48, 0xe0, 0xc8];
+    #[cfg(feature = "xt-strict")]
+    const UP_XT : &'static [u8] = &[0x48, 0xc8];
+    #[cfg(not(feature = "xt-strict"))]
+    const PAUSE_XT : &'static [u8] = &[0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5];
+    #[cfg(feature = "xt-strict")]
+    const PAUSE_XT : &'static [u8] = &[0x1d, 0x45, 0xc5, 0x9d];
+    #[cfg(not(feature = "passthrough-unknown"))]
+    const LONE_1F_XT : &'static [u8] = &[];
+    #[cfg(feature = "passthrough-unknown")]
+    const LONE_1F_XT : &'static [u8] = &[0x1f];
+
+    // AT frames in, the XT bytes the PC should end up with.
+    const CASES : &'static [(&'static [u8], &'static [u8])] = &[
+        (&[0x1c, 0xf0, 0x1c], &[0x1e, 0x9e]), // A
+        (&[0x12, 0x1c, 0xf0, 0x1c, 0xf0, 0x12], &[0x2a, 0x1e, 0x9e, 0xaa]), // Shift+A
+        (&[0xe0, 0x75, 0xe0, 0xf0, 0x75], UP_XT), // Up
+        (&[0x58, 0xf0, 0x58], &[0x3a, 0xba]), // Caps Lock
+        (&[0x1f], LONE_1F_XT), // No XT equivalent.
+        (&[0xe1, 0x14, 0x77, 0xe1, 0xf0, 0x14, 0xf0, 0x77], PAUSE_XT), // Pause
+    ];
+
+    // make-only drops the breaks.
+    #[cfg(not(feature = "make-only"))]
+    #[test]
+    fn table() {
+        for &(at, xt) in CASES.iter() {
+            let mut sim = Sim::new();
+            for b in at.iter() {
+                sim.at_frame(*b);
+            }
+
+            let mut out = [0; 12];
+            let n = sim.xt_bytes(&mut out);
+            assert_eq!(&out[..n], xt, "AT {:x?}", at);
+        }
+    }
+
+    #[test]
+    fn lines_idle_high_after_each_byte() {
+        let mut sim = Sim::new();
+        sim.at_frame(0x1c);
+
+        let t = sim.transitions();
+        // Ten clock pulses, each a fall and a rise; data changes in between.
+        assert_eq!(t.iter().filter(|l| !l.clk).count() >= 10, true);
+        assert_eq!(t[t.len() - 1], XtLines { clk : true, data : true });
+    }
+
+    #[test]
+    fn caps_lock_lights_led() {
+        let mut sim = Sim::new();
+        sim.at_frame(0x58);
+        assert_eq!(sim.leds(), LED_CAPS);
+        sim.at_frame(0xf0);
+        sim.at_frame(0x58);
+        sim.at_frame(0x58);
+        assert_eq!(sim.leds(), 0);
+    }
+
+    // The PC resets us with a key half received and two more frames waiting. The order is
+    // fixed: what already went out stays out, then the BAT code, then the waiting frames
+    // are thrown away. Nothing of the half-received key is left behind afterwards.
+    #[test]
+    fn host_reset_with_keys_waiting() {
+        let mut sim = Sim::new();
+        sim.at_frame(0x14); // Left Ctrl, held
+        sim.at_frame(0xf0);
+        sim.queue_at_frame(0x14);
+        sim.queue_at_frame(0x1c); // A
+        sim.host_reset();
+
+        // No 0x9d for Ctrl: the PC forgets held keys on a reset, and so do we.
+        let mut out = [0; 12];
+        let n = sim.xt_bytes(&mut out);
+        assert_eq!(&out[..n], &[0x1d, 0xaa]);
+
+        sim.at_frame(0x32); // B
+        let n = sim.xt_bytes(&mut out);
+        assert_eq!(&out[..n], &[0x1d, 0xaa, 0x30]);
+    }
+
+    #[test]
+    fn bad_parity_dropped() {
+        let mut sim = Sim::new();
+        // 0x1c with its parity bit flipped.
+        let bits = [false, false, false, true, true, true, false, false, false, true, true];
+        sim.at_bits(&bits);
+
+        let mut out = [0; 12];
+        assert_eq!(sim.xt_bytes(&mut out), 0);
+    }
+}
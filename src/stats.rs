@@ -0,0 +1,53 @@
+use msp430_atomic::{AtomicU8, AtomicU16};
+
+// Running counts of things that go wrong (and one of things that go right, for scale),
+// for field reports. Keycodes lost to a full IN_BUFFER aren't here: the buffer counts
+// those itself, stats or not (KeycodeBuffer::dropped). All saturate rather than wrap: a
+// counter stuck at its maximum says "a lot", one that wrapped says something misleading.
+// Each counter is only ever bumped from one context, the interrupt or the idle loop, so a
+// load and a store is enough.
+pub struct Stats {
+    pub frames : AtomicU16, // Complete frames from the keyboard, good or bad.
+    pub parity_errors : AtomicU8, // Frames with a bad start, stop or parity bit.
+    pub resends : AtomicU8, // Resend requests we sent for those.
+    pub host_resets : AtomicU8,
+    pub ack_timeouts : AtomicU8, // Commands the keyboard never clocked in.
+}
+
+impl Stats {
+    pub const fn new() -> Stats {
+        Stats {
+            frames : AtomicU16::new(0),
+            parity_errors : AtomicU8::new(0),
+            resends : AtomicU8::new(0),
+            host_resets : AtomicU8::new(0),
+            ack_timeouts : AtomicU8::new(0),
+        }
+    }
+
+    // Interrupt.
+    pub fn count_frame(&self) -> () {
+        self.frames.store(self.frames.load().saturating_add(1));
+    }
+
+    pub fn count_parity_error(&self) -> () {
+        bump(&self.parity_errors);
+    }
+
+    // Idle loop.
+    pub fn count_resend(&self) -> () {
+        bump(&self.resends);
+    }
+
+    pub fn count_host_reset(&self) -> () {
+        bump(&self.host_resets);
+    }
+
+    pub fn count_ack_timeout(&self) -> () {
+        bump(&self.ack_timeouts);
+    }
+}
+
+fn bump(count : &AtomicU8) -> () {
+    count.store(count.load().saturating_add(1));
+}
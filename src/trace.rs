@@ -0,0 +1,51 @@
+use keyfsm::{Cmd, ProcReply};
+
+pub const TRACE_LEN : usize = 8;
+
+// ProcReply has more variants than a nibble holds, so each entry is nine bits: the Cmd
+// tag (1-15) above REPLY_BITS bits of ProcReply tag. That's still three octal digits when
+// blinked out.
+const REPLY_BITS : u8 = 5;
+
+// The last TRACE_LEN trips through the idle loop, one entry each. Payloads (which key,
+// which LED) aren't kept; the shape of the sequence is usually what matters. Once frozen,
+// new trips are dropped, so the ones before a failure survive until somebody looks at
+// them.
+pub struct Trace {
+    next : u8,
+    frozen : bool,
+    entries : [u16; TRACE_LEN],
+}
+
+impl Trace {
+    pub const fn new() -> Trace {
+        Trace {
+            next : 0,
+            frozen : false,
+            entries : [0; TRACE_LEN],
+        }
+    }
+
+    pub fn record(&mut self, cmd : &Cmd, reply : &ProcReply) -> () {
+        if self.frozen {
+            return;
+        }
+        let entry : u16 = ((cmd.tag() as u16) << REPLY_BITS) | (reply.tag() as u16);
+        self.entries[self.next as usize] = entry;
+        self.next = ((self.next as usize + 1) % TRACE_LEN) as u8;
+    }
+
+    // Keeps the first failure's trace; later ones don't overwrite it.
+    pub fn freeze(&mut self) -> () {
+        self.frozen = true;
+    }
+
+    pub fn thaw(&mut self) -> () {
+        self.frozen = false;
+    }
+
+    // Entry 0 is the oldest.
+    pub fn entry(&self, idx : usize) -> u16 {
+        self.entries[(self.next as usize + idx) % TRACE_LEN]
+    }
+}